@@ -1,439 +1,7044 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        ConnectInfo, FromRef, Path, Query, Request, State,
+    },
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
     },
-    response::{Html, IntoResponse},
     routing::{get, post},
     Json, Router,
 };
+use clap::{CommandFactory, Parser};
+use ed25519_dalek::{Signature, SigningKey, Verifier, VerifyingKey};
+use flate2::write::ZlibEncoder;
+use futures_util::stream::{self, Stream};
 use rand::distributions::WeightedIndex;
 use rand::prelude::Distribution;
-use rand::rngs::StdRng;
+use rand::rngs::{OsRng, StdRng};
 use rand::{Rng, SeedableRng};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::{
-    sync::Arc,
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fmt,
+    future::Future,
+    io::{self, Write},
+    iter::Sum,
+    net::{IpAddr, SocketAddr},
+    ops::{Add, AddAssign, Div, Mul, Sub, SubAssign},
+    path::{Path as FsPath, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
     time::{SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::{broadcast, Mutex};
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    sync::{broadcast, broadcast::error::RecvError, mpsc, oneshot, Notify, RwLock},
+};
+use tower_http::{
+    compression::CompressionLayer,
+    cors::{Any, CorsLayer},
+    trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
+};
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use utoipa_swagger_ui::{Config as SwaggerConfig, SwaggerUi};
 
 const RATE: f64 = 10.0 / 27.0; // ~37.04% base, 33.33% effective after 10% emission
 const PRATE: f64 = RATE * 10.0; // vesting rate
 const SPY: f64 = 365.25 * 24.0 * 3600.0; // seconds per year
-const TOTAL_SUPPLY: f64 = 1_000_000_000.0;
-const GIFT_ALICE: f64 = 10_000_000.0; // 1%
-const GIFT_REST: f64 = 90_000_000.0;  // 9% divided randomly among remaining 997
+const FEE_RATE: f64 = 0.001; // 0.1% on wallet-to-wallet transfers
+const LIQUID_SHARE: f64 = 1.0 / 3.0; // of what a wallet receives, spendable at once
+const LOCKED_SHARE: f64 = 2.0 / 3.0; // and locked to vest
+const EARLY_FEE: f64 = 1.0 / 3.0; // on locked funds withdrawn before they vest
+const EARLY_AVAILABLE: f64 = 0.75; // of locked funds that can be withdrawn early
+const TOTAL_SUPPLY: Money = Money::koi(1_000_000_000);
+const MAX_SUPPLY: Money = Money(i64::MAX as i128); // so every amount fits an i64 of nano-Koi
+const GIFT_ALICE: Money = Money::koi(10_000_000); // 1%
+const GIFT_REST: Money = Money::koi(90_000_000);  // 9% divided randomly among remaining 997
+const NAMED: [&str; 8] = [
+    "Koi",
+    "Alice",
+    "Bob",
+    "Carol",
+    "Dan",
+    "Eve",
+    "Millionaire",
+    "Staking",
+];
 const MILLIONAIRE_IDX: usize = 6;
-const MILLIONAIRE_PAYOUT: f64 = 1_000_000.0;
-const MILLIONAIRE_THRESHOLD: f64 = 1_001_001.0;
+const STAKING_IDX: usize = 7;
+const MIN_TX_AMOUNT: Money = Money::koi(1); // smaller wallet-to-wallet sends are dust
+const REFERRAL_BONUS: Money = Money::koi(10); // from Koi to whoever first pays a wallet, if enabled
+const VESTING_CLIFF: f64 = 0.0; // seconds newly locked funds wait before vesting
+const HALVING_INTERVAL: f64 = 0.0; // seconds between emission halvings; 0 never halves
+const STAKE_RATE: f64 = 0.05; // annual reward on staked funds, paid by Koi
+const PAYOUT_COOLDOWN: f64 = 0.0; // seconds between payouts of one contract; 0 for none
+const MIN_CONTRIBUTORS: usize = 1; // 2 or more so nobody wins back only their own deposits
+const MILLIONAIRE_SHARE: f64 = 1.0; // of the balance; excess over the threshold rolls into the prize
+const MILLIONAIRE_THRESHOLD: Money = Money::koi(1_001_001);
+const LOG_CAP: usize = 1000; // most recent transactions kept in memory
+const IDEMPOTENCY_CAP: usize = 10_000; // keyed send responses remembered
+const IDEMPOTENCY_TTL: f64 = 24.0 * 3600.0; // seconds a key is remembered
+const NOTIFY_WINDOW: f64 = 0.1; // seconds of changes folded into one notification
+const WEBHOOK_ATTEMPTS: u32 = 3; // per payout, backing off 1s then 2s
+const INTEREST_LOG_MIN: Money = Money::koi(1); // smaller payouts aren't logged
+const MAX_RATE_MULTIPLIER: f64 = 100.0; // on one wallet's share of emission
+const MAX_NAME_LEN: usize = 32;
+const MAX_SCHEDULE_STEPS: usize = 1000; // points one vesting schedule returns at most
+const QUEUE_CAPACITY: usize = 1024; // changes waiting for the worker before new ones get 503
+const QUEUE_BATCH: usize = 64; // changes applied under one acquisition of the state lock
+const COLD_SWEEP: f64 = 60.0; // seconds between settling the wallets outside the hot set
+const SNAPSHOT_VERSION: u32 = 1; // bump when a Snapshot field changes meaning or goes away
 
-fn now() -> f64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f64()
+/// Tokenomics parameters. Defaults are the constants above; `from_env`
+/// lets each be overridden at startup without recompiling.
+#[derive(Clone, Serialize, ToSchema)]
+struct Config {
+    rate: f64,
+    prate: f64,
+    supply: Money,
+    gift_alice: Money,
+    gift_rest: Money,
+    fee_rate: f64,
+    liquid_share: f64, // with locked_share, how received funds split; they sum to 1
+    locked_share: f64,
+    early_fee: f64, // with early_available, what withdrawing unvested funds costs
+    early_available: f64,
+    millionaire_share: f64,
+    payout_cooldown: f64,
+    min_contributors: usize,
+    exclude_top_contributor: bool,
+    prize_split: Vec<f64>, // shares of each payout for the 1st, 2nd, ... winner
+    contribution_decay: f64, // weight contributions keep after a payout
+    stake_rate: f64,
+    halving_interval: f64,
+    vesting_cliff: f64,
+    min_tx_amount: Money,
+    log_cap: usize,              // most recent transactions kept in memory
+    fee_tiers: Vec<FeeTier>,     // by ascending share; the highest one reached replaces fee_rate
+    contracts: Vec<ContractCfg>, // lotteries besides Millionaire, in the last wallet slots
+    referral: bool,
+    referral_bonus: Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gifts: Option<BTreeMap<String, Money>>, // by wallet name, replacing the random split
+    #[serde(skip_serializing_if = "Option::is_none")]
+    genesis: Option<f64>, // Unix seconds a fresh economy dates from, else its creation
+    // Leading wallets the simulation moves and snapshots carry; the rest
+    // only change through the API. All of them when None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hot_set: Option<usize>,
 }
 
-#[derive(Clone, Serialize)]
-struct Wallet {
-    name: String,
-    contract: bool,
-    locked: f64,
-    vested: f64,
-    balance: f64,
-    interest: f64,
-    sent: f64,
-    t: f64,
+/// Fee `rate` for transfers of at least `share` of the sender's balance.
+#[derive(Clone, Serialize, ToSchema)]
+struct FeeTier {
+    share: f64,
+    rate: f64,
 }
 
-#[derive(Clone, Serialize)]
-struct TxLog {
-    from: String,
-    to: String,
-    amount: f64,
-    fee: f64,
-    t: f64,
-}
+impl FromStr for FeeTier {
+    type Err = String;
 
-#[derive(Serialize)]
-struct Snapshot {
-    wallets: Vec<Wallet>,
-    log: Vec<TxLog>,
-    rate: f64,
-    prate: f64,
-    spy: f64,
-    supply: f64,
-    k0: f64,
-    t: f64,
+    /// `share:rate`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("invalid fee tier {s:?}, expected share:rate");
+        match s.split(':').collect::<Vec<_>>()[..] {
+            [share, rate] => Ok(FeeTier {
+                share: share.parse().map_err(|_| bad())?,
+                rate: rate.parse().map_err(|_| bad())?,
+            }),
+            _ => Err(bad()),
+        }
+    }
 }
 
-struct App {
-    wallets: Vec<Wallet>,
-    log: Vec<TxLog>,
-    contributions: Vec<f64>,
-    rng: StdRng,
-    notify: broadcast::Sender<()>,
+#[derive(Clone, Serialize, ToSchema)]
+struct ContractCfg {
+    name: String,
+    threshold: Money,
+    share: f64,
 }
 
-impl App {
-    fn new(notify: broadcast::Sender<()>) -> Self {
-        let t = now();
-        let named = ["Koi", "Alice", "Bob", "Carol", "Dan", "Eve", "Millionaire"];
-        let n = 100;
-        let mut wallets = Vec::with_capacity(n);
-
-        for i in 0..n {
-            let name: String = if i < named.len() {
-                named[i].into()
-            } else {
-                format!("W{:05}", i)
-            };
-            wallets.push(Wallet {
-                name,
-                contract: i == MILLIONAIRE_IDX,
-                locked: 0.0,
-                vested: 0.0,
-                balance: 0.0,
-                interest: 0.0,
-                sent: 0.0,
-                t,
-            });
-        }
-
-        wallets[0].balance = TOTAL_SUPPLY;
+impl FromStr for ContractCfg {
+    type Err = String;
 
-        // Compute gift amounts (skip contracts)
-        let mut gifts = vec![0.0; n];
-        gifts[1] = GIFT_ALICE;
-        let mut rng = rand::thread_rng();
-        let weights: Vec<f64> = (2..n)
-            .map(|i| if wallets[i].contract { 0.0 } else { rng.gen::<f64>() })
-            .collect();
-        let sum: f64 = weights.iter().sum();
-        for (i, w) in weights.iter().enumerate() {
-            gifts[i + 2] = GIFT_REST * w / sum;
+    /// `name:threshold:share`, the threshold in Koi.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let bad = || format!("invalid contract {s:?}, expected name:threshold:share");
+        let koi = |v: &str| v.parse::<f64>().map(Money::from_f64).map_err(|_| bad());
+        match s.split(':').collect::<Vec<_>>()[..] {
+            [name, threshold, share] if !name.is_empty() => Ok(ContractCfg {
+                name: name.into(),
+                threshold: koi(threshold)?,
+                share: share.parse().map_err(|_| bad())?,
+            }),
+            _ => Err(bad()),
         }
+    }
+}
 
-        let log = Vec::new();
-        let contributions = vec![0.0; n];
-        let rng = StdRng::from_entropy();
-        let mut app = App { wallets, log, contributions, rng, notify };
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            rate: RATE,
+            prate: PRATE,
+            supply: TOTAL_SUPPLY,
+            gift_alice: GIFT_ALICE,
+            gift_rest: GIFT_REST,
+            fee_rate: FEE_RATE,
+            liquid_share: LIQUID_SHARE,
+            locked_share: LOCKED_SHARE,
+            early_fee: EARLY_FEE,
+            early_available: EARLY_AVAILABLE,
+            millionaire_share: MILLIONAIRE_SHARE,
+            payout_cooldown: PAYOUT_COOLDOWN,
+            min_contributors: MIN_CONTRIBUTORS,
+            exclude_top_contributor: false,
+            prize_split: vec![1.0],
+            contribution_decay: 0.0,
+            stake_rate: STAKE_RATE,
+            halving_interval: HALVING_INTERVAL,
+            vesting_cliff: VESTING_CLIFF,
+            min_tx_amount: MIN_TX_AMOUNT,
+            log_cap: LOG_CAP,
+            fee_tiers: Vec::new(),
+            contracts: Vec::new(),
+            referral: false,
+            referral_bonus: REFERRAL_BONUS,
+            gifts: None,
+            genesis: None,
+            hot_set: None,
+        }
+    }
+}
 
-        // Send gifts as real transactions (skip contracts)
-        for i in 1..n {
-            if app.wallets[i].contract {
-                continue;
+impl Config {
+    fn from_env() -> Result<Self, String> {
+        let d = Config::default();
+        let rate = env_parse("RATE")?.unwrap_or(d.rate);
+        // Vesting keeps its 10x ratio to emission unless set explicitly
+        let prate = env_parse("PRATE")?.unwrap_or(rate * 10.0);
+        let koi = |name| env_parse::<f64>(name).map(|v| v.map(Money::from_f64));
+        let cfg = Config {
+            rate,
+            prate,
+            supply: koi("TOTAL_SUPPLY")?.unwrap_or(d.supply),
+            gift_alice: koi("GIFT_ALICE")?.unwrap_or(d.gift_alice),
+            gift_rest: koi("GIFT_REST")?.unwrap_or(d.gift_rest),
+            fee_rate: env_parse("FEE_RATE")?.unwrap_or(d.fee_rate),
+            liquid_share: env_parse("LIQUID_SHARE")?.unwrap_or(d.liquid_share),
+            locked_share: env_parse("LOCKED_SHARE")?.unwrap_or(d.locked_share),
+            early_fee: env_parse("EARLY_FEE")?.unwrap_or(d.early_fee),
+            early_available: env_parse("EARLY_AVAILABLE")?.unwrap_or(d.early_available),
+            millionaire_share: env_parse("MILLIONAIRE_SHARE")?.unwrap_or(d.millionaire_share),
+            payout_cooldown: env_parse("PAYOUT_COOLDOWN")?.unwrap_or(d.payout_cooldown),
+            min_contributors: env_parse("MIN_CONTRIBUTORS")?.unwrap_or(d.min_contributors),
+            exclude_top_contributor: env_parse("EXCLUDE_TOP_CONTRIBUTOR")?
+                .unwrap_or(d.exclude_top_contributor),
+            prize_split: env_list("PRIZE_SPLIT")?.unwrap_or(d.prize_split),
+            contribution_decay: env_parse("CONTRIBUTION_DECAY")?.unwrap_or(d.contribution_decay),
+            stake_rate: env_parse("STAKE_RATE")?.unwrap_or(d.stake_rate),
+            halving_interval: env_parse("HALVING_INTERVAL")?.unwrap_or(d.halving_interval),
+            vesting_cliff: env_parse("VESTING_CLIFF")?.unwrap_or(d.vesting_cliff),
+            min_tx_amount: koi("MIN_TX_AMOUNT")?.unwrap_or(d.min_tx_amount),
+            log_cap: env_parse("LOG_CAP")?.unwrap_or(d.log_cap),
+            fee_tiers: env_list("FEE_TIERS")?.unwrap_or(d.fee_tiers),
+            contracts: env_list("CONTRACTS")?.unwrap_or(d.contracts),
+            referral: env_parse("REFERRAL")?.unwrap_or(d.referral),
+            referral_bonus: koi("REFERRAL_BONUS")?.unwrap_or(d.referral_bonus),
+            gifts: std::env::var_os("GIFTS_PATH")
+                .map(|path| load_gifts(FsPath::new(&path)))
+                .transpose()?,
+            genesis: env_parse("GENESIS")?,
+            hot_set: env_parse("HOT_SET")?,
+        };
+        if !(cfg.rate >= 0.0 && cfg.prate >= 0.0 && cfg.stake_rate >= 0.0) {
+            return Err("RATE, PRATE and STAKE_RATE must be non-negative".into());
+        }
+        if !(Money::ZERO < cfg.supply && cfg.supply <= MAX_SUPPLY) {
+            return Err(format!(
+                "TOTAL_SUPPLY must be positive and at most {}",
+                MAX_SUPPLY.to_f64()
+            ));
+        }
+        if cfg.gift_alice < Money::ZERO
+            || cfg.gift_rest < Money::ZERO
+            || cfg.gift_alice + cfg.gift_rest > cfg.supply
+        {
+            return Err("gifts must be non-negative and fit within TOTAL_SUPPLY".into());
+        }
+        if let Some(gifts) = &cfg.gifts {
+            if gifts.values().any(|&g| g < Money::ZERO)
+                || gifts.values().copied().sum::<Money>() > cfg.gift_alice + cfg.gift_rest
+            {
+                return Err(
+                    "GIFTS_PATH gifts must be non-negative and total at most GIFT_ALICE + GIFT_REST"
+                        .into(),
+                );
             }
-            let _ = app.send(0, i, gifts[i]);
         }
+        if !(0.0..1.0).contains(&cfg.fee_rate) {
+            return Err("FEE_RATE must be at least 0 and below 1".into());
+        }
+        let fraction = 0.0..=1.0;
+        if !(fraction.contains(&cfg.liquid_share)
+            && fraction.contains(&cfg.locked_share)
+            && (cfg.liquid_share + cfg.locked_share - 1.0).abs() < 1e-9)
+        {
+            return Err("LIQUID_SHARE and LOCKED_SHARE must lie in [0, 1] and sum to 1".into());
+        }
+        // Withdrawing all that's available must leave the fee coverable
+        if !(cfg.early_fee >= 0.0
+            && cfg.early_available > 0.0
+            && cfg.early_available * (1.0 + cfg.early_fee) <= 1.0 + 1e-9)
+        {
+            return Err(
+                "EARLY_FEE must be non-negative and EARLY_AVAILABLE positive, with EARLY_AVAILABLE * (1 + EARLY_FEE) at most 1"
+                    .into(),
+            );
+        }
+        let mut last_share = 0.0;
+        for tier in &cfg.fee_tiers {
+            if !(tier.share > last_share && tier.share <= 1.0 && (0.0..1.0).contains(&tier.rate)) {
+                return Err(
+                    "FEE_TIERS shares must ascend within (0, 1] and rates lie in [0, 1)".into(),
+                );
+            }
+            last_share = tier.share;
+        }
+        if cfg.log_cap == 0 {
+            return Err("LOG_CAP must be at least 1".into());
+        }
+        if cfg.referral_bonus < Money::ZERO {
+            return Err("REFERRAL_BONUS must be non-negative".into());
+        }
+        if cfg.genesis.is_some_and(|g| !(g >= 0.0 && g.is_finite())) {
+            return Err("GENESIS must be a non-negative Unix timestamp".into());
+        }
+        if cfg.hot_set.is_some_and(|k| k < NAMED.len()) {
+            return Err(format!("HOT_SET must be at least {}", NAMED.len()));
+        }
+        if !(0.0..).contains(&cfg.halving_interval) {
+            return Err("HALVING_INTERVAL must be non-negative".into());
+        }
+        if !(0.0..).contains(&cfg.vesting_cliff) {
+            return Err("VESTING_CLIFF must be non-negative".into());
+        }
+        if !(0.0..).contains(&cfg.payout_cooldown) {
+            return Err("PAYOUT_COOLDOWN must be non-negative".into());
+        }
+        if !(0.0..1.0).contains(&cfg.contribution_decay) {
+            return Err("CONTRIBUTION_DECAY must be at least 0 and below 1".into());
+        }
+        let share_ok = |s: f64| s > 0.0 && s <= 1.0;
+        if !cfg.prize_split.iter().all(|&s| share_ok(s))
+            || cfg.prize_split.iter().sum::<f64>() > 1.0 + 1e-9
+        {
+            return Err("PRIZE_SPLIT shares must be positive and sum to at most 1".into());
+        }
+        if !share_ok(cfg.millionaire_share) {
+            return Err("MILLIONAIRE_SHARE must be above 0 and at most 1".into());
+        }
+        for (k, c) in cfg.contracts.iter().enumerate() {
+            if !share_ok(c.share) {
+                return Err(format!(
+                    "contract {}: share must be above 0 and at most 1",
+                    c.name
+                ));
+            }
+            if !valid_name(&c.name) {
+                return Err(format!("contract {}: {}", c.name, TxError::InvalidName));
+            }
+            if NAMED.contains(&c.name.as_str())
+                || cfg.contracts[..k].iter().any(|o| o.name == c.name)
+            {
+                return Err(format!("contract {}: name already taken", c.name));
+            }
+        }
+        Ok(cfg)
+    }
 
-        app
+    /// Fee rate on sending `amount` out of `balance`.
+    fn fee_rate_for(&self, amount: Money, balance: Money) -> f64 {
+        let share = amount.to_f64() / balance.to_f64();
+        self.fee_tiers
+            .iter()
+            .rev()
+            .find(|tier| share >= tier.share)
+            .map_or(self.fee_rate, |tier| tier.rate)
     }
 
-    fn settle(&mut self, i: usize) {
-        let t = now();
-        if i == 0 || self.wallets[i].contract {
-            return;
+    /// Halvings of the base rate due `age` seconds after genesis.
+    fn halvings(&self, age: f64) -> f64 {
+        if self.halving_interval > 0.0 {
+            (age / self.halving_interval).floor().max(0.0)
+        } else {
+            0.0
         }
+    }
 
-        let w = &self.wallets[i];
-        let dt = (t - w.t) / SPY;
-
-        let vested = (w.locked * ((PRATE * dt).exp() - 1.0)).min(w.locked);
-        let erate = RATE * self.wallets[0].balance.max(0.0) / TOTAL_SUPPLY;
-        let interest = (w.balance + w.vested + vested) * ((erate * dt).exp() - 1.0);
-
-        self.wallets[i].balance += interest;
-        self.wallets[i].interest += interest;
-        self.wallets[i].vested += vested;
-        self.wallets[i].locked -= vested;
-        self.wallets[i].t = t;
+    /// Base emission rate `age` seconds after genesis.
+    fn rate_at(&self, age: f64) -> f64 {
+        self.rate * 0.5f64.powf(self.halvings(age))
+    }
 
-        // Only interest funded by Koi
-        self.wallets[0].balance -= interest;
+    /// Annual emission rate, scaled down as Koi's balance is paid out.
+    fn erate(&self, koi: Money, age: f64) -> f64 {
+        self.rate_at(age) * koi.max(Money::ZERO).to_f64() / self.supply.to_f64()
     }
+}
 
-    fn early_settle(&mut self, i: usize, amount: f64) -> Result<(), String> {
-        if i == 0 {
-            return Err("Koi cannot settle".into());
-        }
-        if amount < 0.0 {
-            return Err("Amount must be non-negative".into());
-        }
+fn env_parse<T: FromStr>(name: &str) -> Result<Option<T>, String> {
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid {name}: {v:?}")),
+        Err(_) => Ok(None),
+    }
+}
 
-        self.settle(i);
+/// Comma-separated values, skipping empty items.
+fn env_list<T: FromStr>(name: &str) -> Result<Option<Vec<T>>, String>
+where
+    T::Err: fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) => v
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse().map_err(|e| format!("invalid {name}: {e}")))
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Err(_) => Ok(None),
+    }
+}
 
-        if amount > 0.0 {
-            let available = 3.0 * self.wallets[i].locked / 4.0;
-            if amount > available {
-                return Err("Exceeds available".into());
-            }
-        }
+/// Fixed-point amount in nano-Koi. Transfers and fees are exact integer
+/// arithmetic so holdings always sum to supply; only interest and vesting
+/// pass through f64. Serialized as a float number of Koi, except in the
+/// state file, which keeps the exact integer (see `exact`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Money(i128);
 
-        // Move all vested to balance (free), reset interest counter
-        let claimed = self.wallets[i].vested;
-        self.wallets[i].balance += claimed;
-        self.wallets[i].vested = 0.0;
-        self.wallets[i].interest = 0.0;
+impl Money {
+    const ZERO: Money = Money(0);
+    const UNIT: i128 = 1_000_000_000;
 
-        // Early settlement: wallet gets amount, fee goes to Koi
-        if amount > 0.0 {
-            let fee = amount / 3.0;
-            self.wallets[i].balance += amount;
-            self.wallets[i].locked -= amount + fee;
-            self.wallets[0].balance += fee;
-        }
+    const fn koi(n: i64) -> Self {
+        Money(n as i128 * Self::UNIT)
+    }
 
-        let t = now();
-        let name = self.wallets[i].name.clone();
-        let total = claimed + amount;
-        if total > 0.0 {
-            self.log.push(TxLog {
-                from: name.clone(),
-                to: name.clone(),
-                amount: total,
-                fee: if amount > 0.0 { amount / 3.0 } else { 0.0 },
-                t,
-            });
-        }
+    /// Rounds to the nearest nano-Koi; NaN becomes zero and infinities saturate.
+    fn from_f64(v: f64) -> Self {
+        Money((v * Self::UNIT as f64).round() as i128)
+    }
 
-        let _ = self.notify.send(());
-        Ok(())
+    fn to_f64(self) -> f64 {
+        self.0 as f64 / Self::UNIT as f64
     }
+}
 
-    fn send(&mut self, from: usize, to: usize, amount: f64) -> Result<(), String> {
-        if from == to {
-            return self.early_settle(from, amount);
-        }
-        if amount <= 0.0 {
-            return Err("Amount must be positive".into());
-        }
+impl Add for Money {
+    type Output = Money;
+    fn add(self, o: Money) -> Money {
+        Money(self.0 + o.0)
+    }
+}
 
-        self.settle(from);
+impl Sub for Money {
+    type Output = Money;
+    fn sub(self, o: Money) -> Money {
+        Money(self.0 - o.0)
+    }
+}
 
-        if self.wallets[from].balance < amount {
-            return Err("Insufficient balance".into());
-        }
-        self.wallets[from].balance -= amount;
-        self.wallets[from].sent += amount;
+impl AddAssign for Money {
+    fn add_assign(&mut self, o: Money) {
+        self.0 += o.0;
+    }
+}
 
-        // 0.1% fee on wallet-to-wallet (not involving Koi)
-        let mut send_amount = amount;
-        let fee = if from != 0 && to != 0 {
-            let fee = amount / 1000.0;
-            let mut rem = fee;
+impl SubAssign for Money {
+    fn sub_assign(&mut self, o: Money) {
+        self.0 -= o.0;
+    }
+}
 
-            // Source fee: locked first
-            let fl = rem.min(self.wallets[from].locked);
-            self.wallets[from].locked -= fl;
-            rem -= fl;
+impl Mul<i128> for Money {
+    type Output = Money;
+    fn mul(self, k: i128) -> Money {
+        Money(self.0 * k)
+    }
+}
 
-            // Then vested
-            let fv = rem.min(self.wallets[from].vested);
-            self.wallets[from].vested -= fv;
-            rem -= fv;
+/// Scale by a rate, truncating toward zero to a whole nano-Koi.
+impl Mul<f64> for Money {
+    type Output = Money;
+    fn mul(self, k: f64) -> Money {
+        Money((self.0 as f64 * k) as i128)
+    }
+}
 
-            // Then excess balance (already deducted amount)
-            let fb = rem.min(self.wallets[from].balance);
-            self.wallets[from].balance -= fb;
-            rem -= fb;
+impl Div<i128> for Money {
+    type Output = Money;
+    fn div(self, k: i128) -> Money {
+        Money(self.0 / k)
+    }
+}
 
-            // Remainder reduces the transfer amount
-            send_amount -= rem;
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
 
-            self.wallets[0].balance += fee;
-            fee
-        } else {
-            0.0
-        };
+thread_local! {
+    static EXACT: Cell<bool> = const { Cell::new(false) };
+}
 
-        if to == MILLIONAIRE_IDX {
-            self.contributions[from] += send_amount;
+/// Run `f` with `Money` serialized as an integer count of nano-Koi, as the
+/// state file stores it. Floats still read as Koi, which is how files
+/// written before amounts were exact hold them.
+fn exact<R>(f: impl FnOnce() -> R) -> R {
+    struct Reset;
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            EXACT.with(|e| e.set(false));
         }
+    }
+    EXACT.with(|e| e.set(true));
+    let _reset = Reset;
+    f()
+}
 
-        self.settle(to);
-        if to == 0 || self.wallets[to].contract {
-            self.wallets[to].balance += send_amount;
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        if EXACT.with(Cell::get) {
+            s.serialize_i64(self.0 as i64)
         } else {
-            self.wallets[to].balance += send_amount / 3.0;
-            self.wallets[to].locked += 2.0 * send_amount / 3.0;
+            s.serialize_f64(self.to_f64())
         }
+    }
+}
 
-        let t = now();
-        let from_name = self.wallets[from].name.clone();
-        let to_name = self.wallets[to].name.clone();
-        self.log.push(TxLog {
-            from: from_name,
-            to: to_name,
-            amount: send_amount,
-            fee,
-            t,
-        });
-
-        let _ = self.notify.send(());
-        Ok(())
+/// Documented as a plain number of Koi, which is how it goes over the wire.
+impl utoipa::PartialSchema for Money {
+    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
+        utoipa::openapi::ObjectBuilder::new()
+            .schema_type(utoipa::openapi::schema::Type::Number)
+            .description(Some("Koi, to nine decimal places"))
+            .into()
     }
+}
 
-    fn check_millionaire(&mut self) {
-        if self.wallets[MILLIONAIRE_IDX].balance <= MILLIONAIRE_THRESHOLD {
-            return;
+impl ToSchema for Money {}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct Nanos;
+        impl serde::de::Visitor<'_> for Nanos {
+            type Value = Money;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("nano-Koi as an integer, or Koi as a float")
+            }
+            fn visit_i64<E>(self, v: i64) -> Result<Money, E> {
+                Ok(Money(v.into()))
+            }
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Money, E> {
+                i64::try_from(v)
+                    .map(|v| Money(v.into()))
+                    .map_err(|_| E::custom("amount out of range"))
+            }
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Money, E> {
+                koi(v)
+            }
         }
-        // Build weighted distribution from contributions
-        let weights: Vec<(usize, f64)> = self
-            .contributions
-            .iter()
-            .enumerate()
-            .filter(|(_, &c)| c > 0.0)
-            .map(|(i, &c)| (i, c))
-            .collect();
-        if weights.is_empty() {
-            return;
+        // NaN would silently become zero and infinities would saturate
+        fn koi<E: serde::de::Error>(v: f64) -> Result<Money, E> {
+            if !v.is_finite() {
+                return Err(E::custom("amount must be a finite number"));
+            }
+            Ok(Money::from_f64(v))
         }
-        let dist = WeightedIndex::new(weights.iter().map(|&(_, w)| w)).unwrap();
-        let winner = weights[dist.sample(&mut self.rng)].0;
-        let _ = self.send(MILLIONAIRE_IDX, winner, MILLIONAIRE_PAYOUT);
-        // Reset contributions
-        for c in self.contributions.iter_mut() {
-            *c = 0.0;
+        if EXACT.with(Cell::get) {
+            d.deserialize_any(Nanos)
+        } else {
+            koi(f64::deserialize(d)?)
         }
     }
+}
 
-    fn snapshot(&self) -> Snapshot {
-        Snapshot {
-            wallets: self.wallets.clone(),
-            log: self.log.clone(),
-            rate: RATE,
-            prate: PRATE,
-            spy: SPY,
-            supply: TOTAL_SUPPLY,
-            k0: TOTAL_SUPPLY - GIFT_ALICE - GIFT_REST,
-            t: now(),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum TxError {
+    KoiCannotSettle,
+    NegativeAmount,
+    NonPositiveAmount,
+    ExceedsAvailable,
+    InsufficientBalance,
+    UnknownWallet,
+    NegativeHorizon,
+    HorizonTooLarge,
+    NothingStaked,
+    StakedFunds,
+    BelowMinimum,
+    RateLimited,
+    Unauthorized,
+    BadSignature,
+    BadNonce,
+    InvalidKey,
+    Unsignable,
+    InvalidName,
+    NameTaken,
+    Frozen,
+    Unfreezable,
+    InvalidMultiplier,
+    NoInterest,
+    NothingToUndo,
+    CannotUndo,
+    NotAContract,
+    InvalidContractConfig,
+    QueueFull,
+    InvalidAirdrop,
+    UnknownTx,
+    Evicted,
+    BadCommand,
+    FutureSince,
+    SupplyCap,
+}
+
+impl TxError {
+    fn status(self) -> StatusCode {
+        match self {
+            TxError::UnknownWallet | TxError::NotAContract | TxError::UnknownTx => {
+                StatusCode::NOT_FOUND
+            }
+            TxError::Evicted => StatusCode::GONE,
+            TxError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            TxError::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            TxError::Unauthorized => StatusCode::UNAUTHORIZED,
+            TxError::BadSignature => StatusCode::FORBIDDEN,
+            TxError::BadNonce
+            | TxError::NameTaken
+            | TxError::NothingToUndo
+            | TxError::CannotUndo => StatusCode::CONFLICT,
+            TxError::Frozen => StatusCode::LOCKED,
+            TxError::InsufficientBalance
+            | TxError::ExceedsAvailable
+            | TxError::NothingStaked
+            | TxError::StakedFunds => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::BAD_REQUEST,
         }
     }
 }
 
-type S = Arc<Mutex<App>>;
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TxError::KoiCannotSettle => "Koi cannot settle",
+            TxError::NegativeAmount => "Amount must be non-negative",
+            TxError::NonPositiveAmount => "Amount must be positive",
+            TxError::ExceedsAvailable => "Exceeds available",
+            TxError::InsufficientBalance => "Insufficient balance",
+            TxError::UnknownWallet => "Unknown wallet",
+            TxError::NegativeHorizon => "Horizon must be non-negative",
+            TxError::HorizonTooLarge => "Horizon too large",
+            TxError::NothingStaked => "Nothing staked",
+            TxError::StakedFunds => "Staked funds can only leave by unstaking",
+            TxError::BelowMinimum => "Amount below the minimum transaction",
+            TxError::RateLimited => "Too many requests",
+            TxError::Unauthorized => "Missing or invalid API token",
+            TxError::BadSignature => "Missing or invalid signature",
+            TxError::BadNonce => "Nonce must be one more than the sender's last",
+            TxError::InvalidKey => "Public keys are 64 hex digits of an ed25519 key",
+            TxError::Unsignable => "Koi and contracts don't sign",
+            TxError::InvalidName => "Wallet names are 1 to 32 letters, digits, '_', '-' or '.'",
+            TxError::NameTaken => "Wallet name already taken",
+            TxError::Frozen => "Wallet is frozen",
+            TxError::Unfreezable => "Koi and contracts cannot be frozen",
+            TxError::InvalidMultiplier => "Rate multiplier must be between 0 and 100",
+            TxError::NoInterest => "Koi and contracts earn no interest",
+            TxError::NothingToUndo => "The last transaction isn't an undoable transfer",
+            TxError::CannotUndo => "The transferred funds have already moved on",
+            TxError::NotAContract => "No lottery contract by that name",
+            TxError::InvalidContractConfig => {
+                "Threshold must be positive and share above 0 and at most 1"
+            }
+            TxError::QueueFull => "Too many transfers waiting, try again shortly",
+            TxError::UnknownTx => "No transaction with that id",
+            TxError::Evicted => "Transaction is older than the in-memory log keeps",
+            TxError::BadCommand => "Unrecognised or malformed command",
+            TxError::FutureSince => "since must not be in the future",
+            TxError::SupplyCap => "That would take the supply past its maximum",
+            TxError::InvalidAirdrop => {
+                "Airdrops need ordinary recipients and non-negative weights with a positive sum"
+            }
+        })
+    }
+}
 
-async fn index() -> Html<&'static str> {
-    Html(include_str!("index.html"))
+impl std::error::Error for TxError {}
+
+impl IntoResponse for TxError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status(), Json(SendRes::err(self))).into_response()
+    }
 }
 
-async fn ws_upgrade(ws: WebSocketUpgrade, State(s): State<S>) -> impl IntoResponse {
-    ws.on_upgrade(|sock| ws_handler(sock, s))
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
-async fn ws_handler(mut sock: WebSocket, state: S) {
-    let snap = state.lock().await.snapshot();
-    if let Ok(msg) = serde_json::to_string(&snap) {
-        if sock.send(Message::Text(msg)).await.is_err() {
-            return;
-        }
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).ok()?;
     }
+    Some(out)
+}
+
+fn parse_pubkey(s: &str) -> Option<VerifyingKey> {
+    VerifyingKey::from_bytes(&unhex(s)?).ok()
+}
 
-    let mut rx = state.lock().await.notify.subscribe();
+/// The bytes a sender signs: `from`, `to`, the amount in nano-Koi, and the
+/// nonce, joined by newlines. A 5 Koi send from Bob to Carol with nonce 3
+/// signs `"Bob\nCarol\n5000000000\n3"`. Staking signs a send to Staking.
+fn signing_payload(from: &str, to: &str, amount: Money, nonce: u64) -> String {
+    format!("{from}\n{to}\n{}\n{nonce}", amount.0)
+}
 
-    loop {
-        tokio::select! {
-            r = rx.recv() => {
-                if r.is_err() { break; }
-                let snap = state.lock().await.snapshot();
-                if let Ok(msg) = serde_json::to_string(&snap) {
-                    if sock.send(Message::Text(msg)).await.is_err() { break; }
-                }
-            }
-            msg = sock.recv() => {
-                match msg {
-                    Some(Ok(Message::Close(_))) | None => break,
-                    _ => {}
-                }
-            }
-        }
+/// What a wallet signs to burn: `burn:` on the first line, then as for a
+/// send without the recipient, so `"burn:\nBob\n5000000000\n3"`.
+fn burn_payload(name: &str, amount: Money, nonce: u64) -> String {
+    format!("burn:\n{name}\n{}\n{nonce}", amount.0)
+}
+
+/// What a wallet signs to unstake, e.g. `"unstake:\nBob\n3"`.
+fn unstake_payload(name: &str, nonce: u64) -> String {
+    format!("unstake:\n{name}\n{nonce}")
+}
+
+/// Gini coefficient of `sorted`, ascending: 0 when everyone holds the
+/// same, approaching 1 as one holder has everything.
+fn gini(sorted: &[Money]) -> f64 {
+    let n = sorted.len() as f64;
+    let total: f64 = sorted.iter().map(|m| m.to_f64()).sum();
+    if total <= 0.0 {
+        return 0.0;
     }
+    let weighted: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (i + 1) as f64 * m.to_f64())
+        .sum();
+    2.0 * weighted / (n * total) - (n + 1.0) / n
 }
 
-#[derive(Deserialize)]
-struct SendReq {
-    from: String,
-    to: String,
-    amount: f64,
+/// Names end up in URLs, CSV and the page, so they're kept to a plain
+/// charset rather than escaped everywhere.
+fn valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_-.".contains(&b))
 }
 
-#[derive(Serialize)]
-struct SendRes {
-    ok: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    error: Option<String>,
+fn index_names(wallets: &[Wallet]) -> HashMap<String, usize> {
+    wallets
+        .iter()
+        .enumerate()
+        .map(|(i, w)| (w.name.clone(), i))
+        .collect()
 }
 
-async fn send_handler(State(s): State<S>, Json(req): Json<SendReq>) -> Json<SendRes> {
-    let mut app = s.lock().await;
-    let fi = app.wallets.iter().position(|w| w.name == req.from);
-    let ti = app.wallets.iter().position(|w| w.name == req.to);
-    match (fi, ti) {
-        (Some(f), Some(t)) => match app.send(f, t, req.amount) {
-            Ok(()) => {
-                app.check_millionaire();
-                Json(SendRes { ok: true, error: None })
-            }
-            Err(e) => Json(SendRes { ok: false, error: Some(e) }),
-        },
-        _ => Json(SendRes {
-            ok: false,
-            error: Some("Unknown wallet".into()),
-        }),
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
     }
 }
 
-#[tokio::main]
-async fn main() {
-    let (tx, _) = broadcast::channel(64);
-    let state: S = Arc::new(Mutex::new(App::new(tx)));
+/// Where the App reads the time, in seconds since the Unix epoch, so
+/// something other than the wall clock can drive accrual.
+trait Clock: Send + Sync {
+    fn now(&self) -> f64;
+}
 
-    // Random transactions once per second
-    let sim = state.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        now()
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+struct Wallet {
+    name: String,
+    contract: bool,
+    locked: Money,
+    vested: Money,
+    balance: Money,
+    interest: Money,
+    sent: Money,
+    #[serde(default)]
+    received: Money, // net of fees, liquid and locked shares together
+    #[serde(default)]
+    tx_count: u64,
+    // Locks still inside the vesting cliff, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cliff: Vec<Tranche>,
+    // Hex ed25519 public key that signs for this wallet, issued by the
+    // server or given when the wallet was made; always empty for Koi and
+    // contracts
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pubkey: String,
+    #[serde(default)]
+    nonce: u64, // of the last signed send
+    #[serde(default)]
+    frozen: bool, // can neither send nor receive
+    #[serde(default = "unit")]
+    rate_multiplier: f64, // on the emission rate this wallet earns at
+    #[serde(default)]
+    referred: bool, // has received a wallet-to-wallet transfer
+    t: f64,
+}
+
+fn unit() -> f64 {
+    1.0
+}
+
+/// Funds locked at `t`, which start vesting once the cliff has passed.
+#[derive(Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+struct Tranche {
+    amount: Money,
+    t: f64,
+}
+
+impl Wallet {
+    fn new(name: String, contract: bool, t: f64) -> Self {
+        Wallet {
+            name,
+            contract,
+            locked: Money::ZERO,
+            vested: Money::ZERO,
+            balance: Money::ZERO,
+            interest: Money::ZERO,
+            sent: Money::ZERO,
+            received: Money::ZERO,
+            tx_count: 0,
+            cliff: Vec::new(),
+            pubkey: String::new(),
+            nonce: 0,
+            frozen: false,
+            rate_multiplier: 1.0,
+            referred: false,
+            t,
+        }
+    }
+
+    /// Vesting and interest accrued between the last settle and `t`, with
+    /// emission at the annual rate `erate` scaled by the wallet's multiplier.
+    fn accrual(&self, cfg: &Config, erate: f64, t: f64) -> (Money, Money) {
+        let erate = erate * self.rate_multiplier;
+        // A clock stepping backwards must never produce negative accrual
+        let dt = ((t - self.t) / SPY).max(0.0);
+        let vesting = self.vesting(cfg.vesting_cliff, t);
+        let vested =
+            Money::from_f64(vesting.to_f64() * ((cfg.prate * dt).exp() - 1.0)).min(vesting);
+        let held = (self.balance + self.vested + vested).to_f64();
+        let interest = Money::from_f64(held * ((erate * dt).exp() - 1.0));
+        (vested, interest)
+    }
+
+    /// Locked funds past the cliff at `t`. A tranche whose cliff ends
+    /// between settles vests from the previous settle.
+    fn vesting(&self, cliff: f64, t: f64) -> Money {
+        let held: Money = self
+            .cliff
+            .iter()
+            .filter(|tr| tr.t + cliff > t)
+            .map(|tr| tr.amount)
+            .sum();
+        (self.locked - held).max(Money::ZERO)
+    }
+
+    fn credit(&mut self, vested: Money, interest: Money, t: f64) {
+        self.balance += interest;
+        self.interest += interest;
+        self.vested += vested;
+        self.locked -= vested;
+        self.t = self.t.max(t);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum TxKind {
+    Gift,
+    #[default]
+    Transfer,
+    Settle,
+    EarlySettle,
+    MillionairePayout,
+    Referral,
+    Interest,
+    Unstake,
+    Burn,
+    Mint,
+    Undo, // names the reverted transfer's wallets and amounts
+}
+
+/// A deposit into the staking contract, earning from `t` until withdrawn.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Stake {
+    wallet: usize,
+    amount: Money,
+    t: f64,
+}
+
+/// Lottery state for a contract wallet. Transfers into `idx` add to the
+/// sender's contribution; once the balance passes `threshold`, one
+/// contributor drawn by weight wins `share` of the balance. Without
+/// contributors the balance stays put and rolls into the next round.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct Contract {
+    idx: usize,
+    threshold: Money,
+    share: f64,
+    contributions: Vec<Money>,
+    #[serde(default)]
+    last_payout_t: f64,
+}
+
+#[derive(Clone, Serialize, Deserialize, ToSchema)]
+struct TxLog {
+    #[serde(default)]
+    id: u64,
+    from: String,
+    to: String,
+    amount: Money,
+    fee: Money,
+    #[serde(default)]
+    kind: TxKind,
+    t: f64,
+}
+
+/// Everything a client needs to draw the economy. `version` changes only
+/// when a field is removed or reinterpreted; new fields may appear at any
+/// version, so clients should ignore ones they don't know.
+#[derive(Serialize, ToSchema)]
+struct Snapshot {
+    version: u32,         // SNAPSHOT_VERSION
+    wallets: Vec<Wallet>, // the hot set
+    wallet_count: usize,  // including those outside the hot set
+    log: Vec<TxLog>,
+    rate: f64, // after halvings so far
+    // Yield at Koi's current balance for a rate multiplier of 1. A wallet
+    // with multiplier m earns (1 + apy)^m - 1, as /api/wallet reports
+    apy: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_halving: Option<f64>,
+    genesis: f64,
+    age_seconds: f64, // since genesis
+    prate: f64,
+    spy: f64,
+    supply: f64,
+    k0: f64,
+    burned: Money,
+    minted: Money,
+    fees_collected: Money, // all time, already part of Koi's balance
+    config: Config,
+    contracts: Vec<ContractInfo>,
+    paused: bool,
+    t: f64,
+}
+
+/// A lottery contract's current rules.
+#[derive(Serialize, ToSchema)]
+struct ContractInfo {
+    name: String,
+    threshold: Money,
+    share: f64,
+    last_payout_t: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Stats {
+    koi_balance: Money,
+    total_locked: Money,
+    total_vested: Money,
+    total_circulating: Money,
+    active_wallets: usize,
+    fees_collected: Money,
+    transactions: u64,
+    emission_rate: f64,
+    // Over what each holder has, not counting Koi or contracts
+    gini: f64,
+    min_holdings: Money,
+    median_holdings: Money,
+    max_holdings: Money,
+}
+
+/// Where the supply sits. `koi_balance + circulating + burned` equals
+/// `total_supply`, which includes anything minted; `fees_collected` is
+/// already counted in Koi's balance.
+#[derive(Serialize, ToSchema)]
+struct Supply {
+    total_supply: Money,
+    koi_balance: Money,
+    locked: Money,
+    vested: Money,
+    liquid: Money,
+    circulating: Money,
+    burned: Money,
+    minted: Money,
+    fees_collected: Money,
+}
+
+/// A contract's pot and everyone's current stake in its next draw.
+#[derive(Serialize, ToSchema)]
+struct ContractOdds {
+    contract: String,
+    balance: Money,
+    threshold: Money,
+    total: Money, // of all contributions, eligible or not
+    contributors: Vec<Odds>,
+}
+
+/// `probability` is the chance of taking the first prize if the draw ran
+/// now: zero for frozen wallets and, when configured, the top contributor.
+#[derive(Serialize, ToSchema)]
+struct Odds {
+    name: String,
+    contribution: Money,
+    probability: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Rank {
+    rank: usize,
+    name: String,
+    holdings: Money, // balance + vested + locked
+    balance: Money,
+    vested: Money,
+    locked: Money,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Invariant {
+    ok: bool,
+    total: Money, // held across wallets plus burned
+    drift: Money,
+}
+
+#[derive(Serialize)]
+struct WalletDelta {
+    idx: usize,
+    #[serde(flatten)]
+    wallet: Wallet,
+}
+
+/// Changes since the previous frame sent to a client: wallets that differ
+/// and log entries from id `since` onwards.
+#[derive(Serialize)]
+struct Delta {
+    wallets: Vec<WalletDelta>,
+    log: Vec<TxLog>,
+    paused: bool,
+    t: f64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Frame {
+    Snapshot(Box<Snapshot>),
+    Delta(Delta),
+    Result(SendRes), // answers a client's command
+}
+
+impl Frame {
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    fn message(&self, enc: Wire) -> Message {
+        let msg = match enc.format {
+            Encoding::Json => Message::Text(self.to_json()),
+            Encoding::Msgpack => Message::Binary(rmp_serde::to_vec_named(self).unwrap_or_default()),
+        };
+        match enc.compress {
+            Compress::None => msg,
+            Compress::Deflate => {
+                let mut z = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                let _ = z.write_all(&msg.into_data());
+                Message::Binary(z.finish().unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Wire format a WebSocket client picks with `?format=`. MessagePack frames
+/// keep the JSON field names and go out as binary messages.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+/// With `?compress=deflate`, every frame, in either format, goes out as a
+/// binary message of zlib-compressed bytes. The WebSocket stack has no
+/// permessage-deflate, so compression is done per frame instead, and only
+/// for clients that ask and inflate frames themselves.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Compress {
+    #[default]
+    None,
+    Deflate,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+struct Wire {
+    #[serde(default)]
+    format: Encoding,
+    #[serde(default)]
+    compress: Compress,
+}
+
+impl Wire {
+    // What /api/events sends: JSON text, uncompressed
+    const PLAIN: Wire = Wire {
+        format: Encoding::Json,
+        compress: Compress::None,
+    };
+}
+
+/// A frame bound for many clients, encoded at most once per wire format
+/// and only when some client wants that format.
+struct Shared {
+    frame: Frame,
+    encoded: [OnceLock<Message>; 4], // by format, then compression
+}
+
+impl Shared {
+    fn new(frame: Frame) -> Arc<Self> {
+        Arc::new(Shared {
+            frame,
+            encoded: Default::default(),
+        })
+    }
+
+    fn message(&self, enc: Wire) -> Message {
+        let cell = &self.encoded[enc.format as usize * 2 + enc.compress as usize];
+        cell.get_or_init(|| self.frame.message(enc)).clone()
+    }
+}
+
+/// One coalesced change, shared by every subscriber. `delta` covers log ids
+/// from `since` up to `next_id`.
+#[derive(Clone)]
+struct Update {
+    epoch: u64,
+    since: u64,
+    next_id: u64,
+    delta: Arc<Shared>,
+    snapshot: Arc<Shared>,
+}
+
+/// What the latest wallet-to-wallet transfer changed, so it can be undone.
+/// Wallets are captured after settling, so accrued interest stands.
+struct Undo {
+    ids: std::ops::Range<u64>,             // log entries it pushed
+    wallets: Vec<(usize, Wallet, Wallet)>, // before and after
+    fee: Money,
+    contribution: Option<(usize, usize, Money)>, // contract, contributor, weight added
+    stake: bool,
+}
+
+/// On-disk form of the mutable economy state.
+#[derive(Default, Serialize, Deserialize)]
+struct Persisted {
+    wallets: Vec<Wallet>,
+    log: VecDeque<TxLog>,
+    #[serde(default)]
+    contracts: Vec<Contract>,
+    #[serde(default)]
+    stakes: Vec<Stake>,
+    // Millionaire's contributions, from before contracts were persisted
+    #[serde(default, skip_serializing)]
+    contributions: Vec<Money>,
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default)]
+    fees_collected: Money,
+    #[serde(default)]
+    burned: Money,
+    #[serde(default)]
+    minted: Money,
+    #[serde(default)]
+    genesis: Option<f64>,
+    // Secret keys the server once generated and handed out itself
+    #[serde(default, skip_serializing)]
+    keys: Vec<String>,
+}
+
+impl Persisted {
+    fn save(&self, path: &FsPath) -> io::Result<()> {
+        // Write, sync, then rename so a crash never leaves a torn file
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&exact(|| serde_json::to_vec(self))?)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Bring the state up to `patch`. Replaying one it already covers does
+    /// no harm: patches hold values rather than changes, and log entries
+    /// from before `next_id` are skipped.
+    fn apply(&mut self, patch: &Patch) -> io::Result<()> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what);
+        if patch.full {
+            *self = Persisted::default();
+        }
+        self.wallets.truncate(patch.wallet_count);
+        for (i, w) in &patch.wallets {
+            let n = self.wallets.len();
+            match i.cmp(&n) {
+                std::cmp::Ordering::Less => self.wallets[*i] = w.clone(),
+                std::cmp::Ordering::Equal => self.wallets.push(w.clone()),
+                std::cmp::Ordering::Greater => return Err(invalid("patch skips a wallet")),
+            }
+        }
+        if self.wallets.len() != patch.wallet_count {
+            return Err(invalid("patch is missing wallets"));
+        }
+        self.log
+            .retain(|e| patch.dropped.binary_search(&e.id).is_err());
+        for e in &patch.log {
+            if e.id >= self.next_id {
+                self.log.push_back(e.clone());
+            }
+        }
+        self.next_id = self.next_id.max(patch.next_id);
+        if let Some(contracts) = &patch.contracts {
+            self.contracts = contracts.clone();
+        }
+        for &(k, i, amount) in &patch.contributions {
+            *self
+                .contracts
+                .get_mut(k)
+                .and_then(|c| c.contributions.get_mut(i))
+                .ok_or_else(|| invalid("patch names a missing contribution"))? = amount;
+        }
+        if let Some(stakes) = &patch.stakes {
+            self.stakes = stakes.clone();
+        }
+        self.fees_collected = patch.fees_collected;
+        self.burned = patch.burned;
+        self.minted = patch.minted;
+        self.genesis = Some(patch.genesis);
+        Ok(())
+    }
+
+    /// Apply the patches journaled at `path`, returning how many there
+    /// were. A journal that stops short of this state is older than it,
+    /// say because writing failed, and is left out.
+    fn replay(&mut self, path: &FsPath) -> io::Result<usize> {
+        let bytes = match std::fs::read(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            read => read?,
+        };
+        // Every line is ended as it's written, so whatever follows the
+        // last newline was cut off
+        let mut lines: Vec<&[u8]> = bytes.split(|&b| b == b'\n').collect();
+        lines.pop();
+        let mut patches = Vec::new();
+        for line in lines.into_iter().filter(|l| !l.is_empty()) {
+            let line: PatchLine = exact(|| serde_json::from_slice(line))?;
+            patches.extend(line.patch);
+        }
+        if patches.last().is_none_or(|p| p.next_id < self.next_id) {
+            return Ok(0);
+        }
+        for patch in &patches {
+            self.apply(patch)?;
+        }
+        Ok(patches.len())
+    }
+}
+
+/// What a run of commands changed, journaled after their entries so a
+/// restart can bring the last save up to date. The first patch after
+/// startup or a reset holds everything.
+#[derive(Default, Serialize, Deserialize)]
+struct Patch {
+    full: bool, // replaces the state rather than updating it
+    wallet_count: usize,
+    wallets: Vec<(usize, Wallet)>,             // changed or new
+    log: Vec<TxLog>,                           // new entries
+    dropped: Vec<u64>,                         // ids gone from the log, ascending
+    contributions: Vec<(usize, usize, Money)>, // contract, wallet, amount
+    contracts: Option<Vec<Contract>>,          // all of them, when more than contributions changed
+    stakes: Option<Vec<Stake>>,
+    next_id: u64,
+    fees_collected: Money,
+    burned: Money,
+    minted: Money,
+    genesis: f64,
+}
+
+/// A journal line as replay reads it. Transactions have no `patch` and
+/// are skipped.
+#[derive(Serialize, Deserialize)]
+struct PatchLine {
+    #[serde(default)]
+    patch: Option<Box<Patch>>,
+}
+
+struct App {
+    cfg: Config,
+    wallets: Vec<Wallet>,
+    name_to_idx: HashMap<String, usize>,
+    log: VecDeque<TxLog>,
+    next_id: u64, // id of the next TxLog; also the count ever pushed
+    fees_collected: Money,
+    burned: Money, // removed from supply for good
+    minted: Money, // added to supply, on top of cfg.supply
+    genesis: f64,  // creation time; halvings count from here
+    contracts: Vec<Contract>,
+    stakes: Vec<Stake>,
+    rng: StdRng,
+    sim_paused: bool,
+    notify: broadcast::Sender<Update>,
+    dirty: Arc<Notify>,     // raised on every change; coalesced into `notify`
+    published: Vec<Wallet>, // as of the last Update
+    published_id: u64,
+    journal: Option<mpsc::UnboundedSender<Journal>>,
+    journaled: Option<Box<Persisted>>, // the state as of the last patch
+    webhook: Option<mpsc::UnboundedSender<Payout>>,
+    held: Option<Vec<TxLog>>, // entries kept from the journal and trace until a batch commits
+    epoch: u64,               // bumped on reset and undo so streams resend a full snapshot
+    undo: Option<Undo>,
+    // Successful keyed sends, so a retried request replays its response
+    idempotency: HashMap<String, SendRes>,
+    idempotency_order: VecDeque<(f64, String)>,
+    lag: Arc<LagStats>,
+    clock: Arc<dyn Clock>,
+}
+
+/// How often subscribers fell behind the broadcast channel, for sizing
+/// `--broadcast-capacity`.
+#[derive(Default)]
+struct LagStats {
+    events: AtomicU64,
+    skipped: AtomicU64, // updates lost to those subscribers
+}
+
+impl LagStats {
+    fn record(&self, skipped: u64) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+        self.skipped.fetch_add(skipped, Ordering::Relaxed);
+    }
+}
+
+/// Name of each of `n` wallets and whether it's a contract: the named ones
+/// first and any extra contracts from `cfg` last.
+fn wallet_names(cfg: &Config, n: usize) -> impl Iterator<Item = (String, bool)> + '_ {
+    let first_extra = n - cfg.contracts.len();
+    (0..n).map(move |i| {
+        let name = match NAMED.get(i) {
+            Some(&name) => name.into(),
+            None if i >= first_extra => cfg.contracts[i - first_extra].name.clone(),
+            None => format!("W{:05}", i),
+        };
+        (
+            name,
+            i == MILLIONAIRE_IDX || i == STAKING_IDX || i >= first_extra,
+        )
+    })
+}
+
+impl App {
+    /// Build a fresh economy of `n` wallets, the named ones first and any
+    /// extra contracts from `cfg` last.
+    fn new(
+        cfg: Config,
+        n: usize,
+        notify: broadcast::Sender<Update>,
+        journal: Option<mpsc::UnboundedSender<Journal>>,
+        seed: Option<u64>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let names = wallet_names(&cfg, n).collect();
+        App::new_from(0, cfg, names, notify, journal, seed, clock)
+    }
+
+    /// As `new`, numbering transactions from `first_id` and taking each
+    /// wallet's name, and whether it's a contract, from `names`.
+    fn new_from(
+        first_id: u64,
+        cfg: Config,
+        names: Vec<(String, bool)>,
+        notify: broadcast::Sender<Update>,
+        journal: Option<mpsc::UnboundedSender<Journal>>,
+        seed: Option<u64>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let t = clock.now();
+        let n = names.len();
+        let mut wallets: Vec<Wallet> = names
+            .into_iter()
+            .map(|(name, contract)| Wallet::new(name, contract, t))
+            .collect();
+        let name_to_idx = index_names(&wallets);
+
+        wallets[0].balance = cfg.supply;
+
+        // Compute gift amounts (skip contracts)
+        let mut rng = seeded_rng(seed);
+        let gifts: Vec<Money> = match &cfg.gifts {
+            Some(fixed) => wallets
+                .iter()
+                .map(|w| fixed.get(&w.name).copied().unwrap_or(Money::ZERO))
+                .collect(),
+            None => {
+                let mut gifts = vec![Money::ZERO; n];
+                gifts[1] = cfg.gift_alice;
+                let weights: Vec<f64> = (2..n)
+                    .map(|i| {
+                        if wallets[i].contract {
+                            0.0
+                        } else {
+                            rng.gen::<f64>()
+                        }
+                    })
+                    .collect();
+                let sum: f64 = weights.iter().sum();
+                for (i, w) in weights.iter().enumerate() {
+                    gifts[i + 2] = Money::from_f64(cfg.gift_rest.to_f64() * w / sum);
+                }
+                gifts
+            }
+        };
+
+        let log = VecDeque::with_capacity(cfg.log_cap);
+        let millionaire = (
+            MILLIONAIRE_IDX,
+            MILLIONAIRE_THRESHOLD,
+            cfg.millionaire_share,
+        );
+        let extras = cfg.contracts.iter();
+        let contracts = std::iter::once(millionaire)
+            .chain(extras.map(|c| (name_to_idx[&c.name], c.threshold, c.share)))
+            .map(|(idx, threshold, share)| Contract {
+                idx,
+                threshold,
+                share,
+                contributions: vec![Money::ZERO; n],
+                last_payout_t: 0.0,
+            })
+            .collect();
+        let genesis = cfg.genesis.unwrap_or(t);
+        let mut app = App {
+            cfg,
+            name_to_idx,
+            wallets,
+            log,
+            next_id: first_id,
+            fees_collected: Money::ZERO,
+            burned: Money::ZERO,
+            minted: Money::ZERO,
+            genesis,
+            contracts,
+            stakes: Vec::new(),
+            rng,
+            sim_paused: false,
+            notify,
+            dirty: Arc::new(Notify::new()),
+            journal,
+            journaled: None,
+            webhook: None,
+            held: None,
+            epoch: 0,
+            published: Vec::new(),
+            published_id: 0,
+            idempotency: HashMap::new(),
+            idempotency_order: VecDeque::new(),
+            undo: None,
+            lag: Arc::default(),
+            clock,
+        };
+
+        // Send gifts as real transactions (skip contracts)
+        for (i, &gift) in gifts.iter().enumerate().skip(1) {
+            if app.wallets[i].contract {
+                continue;
+            }
+            let _ = app.send(0, i, gift);
+        }
+
+        app
+    }
+
+    /// Load the state saved at `path`, brought up to date by the patches
+    /// in the journal at `replay`.
+    fn load_from_path(
+        path: &FsPath,
+        replay: Option<&FsPath>,
+        cfg: Config,
+        notify: broadcast::Sender<Update>,
+        journal: Option<mpsc::UnboundedSender<Journal>>,
+        seed: Option<u64>,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        let mut p: Persisted = exact(|| serde_json::from_slice(&bytes))?;
+        if let Some(journal) = replay {
+            let patches = p.replay(journal)?;
+            if patches > 0 {
+                tracing::info!(path = %journal.display(), patches, "replayed journal");
+            }
+        }
+        let mut contracts = p.contracts;
+        if contracts.is_empty() {
+            contracts.push(Contract {
+                idx: MILLIONAIRE_IDX,
+                threshold: MILLIONAIRE_THRESHOLD,
+                share: cfg.millionaire_share,
+                contributions: p.contributions,
+                last_payout_t: 0.0,
+            });
+        }
+        let n = p.wallets.len();
+        if n <= STAKING_IDX
+            || !p.wallets[STAKING_IDX].contract
+            || p.stakes.iter().any(|s| s.wallet >= n)
+            || !contracts
+                .iter()
+                .all(|c| c.idx < n && p.wallets[c.idx].contract && c.contributions.len() == n)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "wallet count mismatch",
+            ));
+        }
+        let mut log = p.log;
+        while log.len() > cfg.log_cap {
+            log.pop_front();
+        }
+        let next_id = p.next_id.max(log.back().map_or(0, |e| e.id + 1));
+        let mut wallets = p.wallets;
+        if !p.keys.is_empty() {
+            // Anyone could download those, so they authenticate nothing.
+            // The wallets go keyless and get fresh keys issued on startup
+            for w in &mut wallets {
+                w.pubkey.clear();
+            }
+        }
+        let app = App {
+            cfg,
+            name_to_idx: index_names(&wallets),
+            wallets,
+            next_id,
+            log,
+            fees_collected: p.fees_collected,
+            burned: p.burned,
+            minted: p.minted,
+            genesis: p.genesis.unwrap_or_else(|| clock.now()),
+            contracts,
+            stakes: p.stakes,
+            rng: seeded_rng(seed),
+            sim_paused: false,
+            notify,
+            dirty: Arc::new(Notify::new()),
+            journal,
+            journaled: None,
+            webhook: None,
+            held: None,
+            epoch: 0,
+            published: Vec::new(),
+            published_id: 0,
+            idempotency: HashMap::new(),
+            idempotency_order: VecDeque::new(),
+            undo: None,
+            lag: Arc::default(),
+            clock,
+        };
+        // Holdings are stored exactly, so a gap means the file was written
+        // for another supply, or before amounts were exact
+        if !app.invariant().ok {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "holdings don't add up to the supply",
+            ));
+        }
+        Ok(app)
+    }
+
+    fn persisted(&self) -> Persisted {
+        Persisted {
+            wallets: self.wallets.clone(),
+            log: self.log.clone(),
+            contracts: self.contracts.clone(),
+            stakes: self.stakes.clone(),
+            contributions: Vec::new(),
+            next_id: self.next_id,
+            fees_collected: self.fees_collected,
+            burned: self.burned,
+            minted: self.minted,
+            genesis: Some(self.genesis),
+            keys: Vec::new(),
+        }
+    }
+
+    fn restore(&mut self, p: Persisted) {
+        self.wallets = p.wallets;
+        self.log = p.log;
+        self.contracts = p.contracts;
+        self.stakes = p.stakes;
+        self.next_id = p.next_id;
+        self.fees_collected = p.fees_collected;
+        self.burned = p.burned;
+        self.minted = p.minted;
+        self.genesis = p.genesis.unwrap_or(self.genesis);
+        self.undo = None;
+    }
+
+    fn save_to_path(&self, path: &FsPath) -> io::Result<()> {
+        self.persisted().save(path)
+    }
+
+    /// Journal what changed since the last patch. Called once the queue
+    /// has applied a run of commands, so patches fall between them.
+    fn journal_patch(&mut self) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let full = self.journaled.is_none();
+        let seen = self.journaled.get_or_insert_with(Box::default);
+        let mut patch = Patch {
+            full,
+            wallet_count: self.wallets.len(),
+            next_id: self.next_id,
+            fees_collected: self.fees_collected,
+            burned: self.burned,
+            minted: self.minted,
+            genesis: self.genesis,
+            stakes: (seen.stakes != self.stakes).then(|| self.stakes.clone()),
+            ..Default::default()
+        };
+        for (i, w) in self.wallets.iter().enumerate() {
+            if seen.wallets.get(i) != Some(w) {
+                patch.wallets.push((i, w.clone()));
+            }
+        }
+        // Both logs are in id order
+        let mut ids = self.log.iter().map(|e| e.id).peekable();
+        for e in &seen.log {
+            while ids.next_if(|&id| id < e.id).is_some() {}
+            if ids.next_if_eq(&e.id).is_none() {
+                patch.dropped.push(e.id);
+            }
+        }
+        patch.log = self
+            .log
+            .iter()
+            .filter(|e| e.id >= seen.next_id)
+            .cloned()
+            .collect();
+        let reshaped = seen.contracts.len() != self.contracts.len()
+            || seen.contracts.iter().zip(&self.contracts).any(|(a, b)| {
+                a.idx != b.idx
+                    || a.threshold != b.threshold
+                    || a.share != b.share
+                    || a.last_payout_t != b.last_payout_t
+                    || a.contributions.len() != b.contributions.len()
+            });
+        if reshaped {
+            patch.contracts = Some(self.contracts.clone());
+        } else {
+            for (k, (a, b)) in seen.contracts.iter().zip(&self.contracts).enumerate() {
+                for (i, (x, y)) in a.contributions.iter().zip(&b.contributions).enumerate() {
+                    if x != y {
+                        patch.contributions.push((k, i, *y));
+                    }
+                }
+            }
+        }
+        let unchanged = !full
+            && patch.wallets.is_empty()
+            && patch.log.is_empty()
+            && patch.dropped.is_empty()
+            && patch.contributions.is_empty()
+            && patch.contracts.is_none()
+            && patch.stakes.is_none()
+            && (seen.next_id, seen.fees_collected, seen.burned, seen.minted)
+                == (self.next_id, self.fees_collected, self.burned, self.minted);
+        if unchanged {
+            return;
+        }
+        let applied = seen.apply(&patch);
+        debug_assert!(applied.is_ok());
+        let _ = journal.send(Journal::Patch(Box::new(patch)));
+    }
+
+    /// Save to `path`, then drop from the journal what the save covers:
+    /// the state is written, synced and renamed into place before the
+    /// journal task empties its file. Whatever is journaled after this
+    /// call waits behind the truncation, so it survives. The work happens
+    /// in the returned future, which doesn't hold the state.
+    fn compact_journal(&self, path: PathBuf) -> impl Future<Output = io::Result<()>> + 'static {
+        let persisted = self.persisted();
+        let truncate = self.journal.as_ref().map(|journal| {
+            let (saved, wait) = oneshot::channel();
+            let (done, truncated) = oneshot::channel();
+            let _ = journal.send(Journal::Truncate { saved: wait, done });
+            (saved, truncated)
+        });
+        async move {
+            let res = tokio::task::spawn_blocking(move || persisted.save(&path))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Some((saved, truncated)) = truncate {
+                let _ = saved.send(res.is_ok());
+                res?;
+                // Without a journal task there's nothing left to truncate
+                return truncated.await.unwrap_or(Ok(()));
+            }
+            res
+        }
+    }
+
+    /// Flag a change for subscribers. Bursts within `NOTIFY_WINDOW` reach
+    /// them as one notification.
+    fn touch(&self) {
+        self.dirty.notify_one();
+    }
+
+    /// How many leading wallets are in the hot set.
+    fn hot(&self) -> usize {
+        let n = self.wallets.len();
+        self.cfg.hot_set.map_or(n, |k| k.min(n))
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.name_to_idx.get(name).copied()
+    }
+
+    /// Append an empty wallet, usable as a recipient right away and, if
+    /// made with a key, as a sender. A contract wallet made this way has no
+    /// payout rule and just holds what it's sent.
+    fn create_wallet(
+        &mut self,
+        name: String,
+        contract: bool,
+        pubkey: Option<&str>,
+    ) -> Result<usize, TxError> {
+        if !valid_name(&name) {
+            return Err(TxError::InvalidName);
+        }
+        if self.name_to_idx.contains_key(&name) {
+            return Err(TxError::NameTaken);
+        }
+        let mut wallet = Wallet::new(name.clone(), contract, self.clock.now());
+        if let Some(pubkey) = pubkey {
+            if contract {
+                return Err(TxError::Unsignable);
+            }
+            parse_pubkey(pubkey).ok_or(TxError::InvalidKey)?;
+            wallet.pubkey = pubkey.to_ascii_lowercase();
+        }
+        let i = self.wallets.len();
+        self.wallets.push(wallet);
+        for c in &mut self.contracts {
+            c.contributions.push(Money::ZERO);
+        }
+        self.name_to_idx.insert(name, i);
+        self.touch();
+        Ok(i)
+    }
+
+    /// Stop or restart wallet `i` sending and receiving. Koi and contracts
+    /// have to keep moving funds, so they can't be frozen.
+    fn set_frozen(&mut self, i: usize, frozen: bool) -> Result<(), TxError> {
+        if i == 0 || self.wallets[i].contract {
+            return Err(TxError::Unfreezable);
+        }
+        self.wallets[i].frozen = frozen;
+        self.touch();
+        Ok(())
+    }
+
+    /// Scale the interest wallet `i` earns from now on; what it accrued at
+    /// the old rate is settled first.
+    fn set_rate_multiplier(&mut self, i: usize, multiplier: f64) -> Result<(), TxError> {
+        if i == 0 || self.wallets[i].contract {
+            return Err(TxError::NoInterest);
+        }
+        if !(0.0..=MAX_RATE_MULTIPLIER).contains(&multiplier) {
+            return Err(TxError::InvalidMultiplier);
+        }
+        self.settle(i);
+        self.wallets[i].rate_multiplier = multiplier;
+        self.touch();
+        Ok(())
+    }
+
+    /// Give every wallet that signs for itself but has no key a fresh
+    /// keypair. Only the public half is kept; each wallet's name and secret
+    /// key go back for the operator to hand to its owner.
+    fn issue_keys(&mut self) -> Vec<(String, SigningKey)> {
+        let keyless = self.wallets.iter_mut().skip(1);
+        let issued: Vec<_> = keyless
+            .filter(|w| !w.contract && w.pubkey.is_empty())
+            .map(|w| {
+                let key = SigningKey::generate(&mut OsRng);
+                w.pubkey = hex(key.verifying_key().as_bytes());
+                (w.name.clone(), key)
+            })
+            .collect();
+        if !issued.is_empty() {
+            self.touch();
+        }
+        issued
+    }
+
+    /// Check that `req` is signed by wallet `from`'s key with `nonce` next.
+    fn verify(&self, from: usize, req: &SendReq, nonce: u64) -> Result<(), TxError> {
+        let payload = signing_payload(&req.from, &req.to, req.amount, req.nonce);
+        self.verify_signed(from, &payload, req.signature.as_deref(), req.nonce, nonce)
+    }
+
+    /// Check that `payload` is signed by wallet `i`'s key and that `nonce`
+    /// is the one after `last`.
+    fn verify_signed(
+        &self,
+        i: usize,
+        payload: &str,
+        signature: Option<&str>,
+        nonce: u64,
+        last: u64,
+    ) -> Result<(), TxError> {
+        let key = parse_pubkey(&self.wallets[i].pubkey).ok_or(TxError::BadSignature)?;
+        let sig = signature
+            .and_then(unhex)
+            .map(|b| Signature::from_bytes(&b))
+            .ok_or(TxError::BadSignature)?;
+        key.verify(payload.as_bytes(), &sig)
+            .map_err(|_| TxError::BadSignature)?;
+        if nonce != last + 1 {
+            return Err(TxError::BadNonce);
+        }
+        Ok(())
+    }
+
+    fn settle(&mut self, i: usize) {
+        let t = self.clock.now();
+        if i == 0 || self.wallets[i].contract {
+            return;
+        }
+
+        let erate = self.cfg.erate(self.wallets[0].balance, t - self.genesis);
+        let (vested, interest) = self.wallets[i].accrual(&self.cfg, erate, t);
+        // Koi can only pay out what it still holds
+        let interest = interest.min(self.wallets[0].balance.max(Money::ZERO));
+        self.wallets[i].credit(vested, interest, t);
+        let cliff = self.cfg.vesting_cliff;
+        self.wallets[i].cliff.retain(|tr| tr.t + cliff > t);
+
+        // Only interest funded by Koi
+        self.wallets[0].balance -= interest;
+        if interest >= INTEREST_LOG_MIN {
+            self.push_log(0, i, interest, Money::ZERO, TxKind::Interest);
+        }
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+    }
+
+    /// What wallet `i` would hold after settling `seconds_ahead` from now.
+    fn project(&self, i: usize, seconds_ahead: f64) -> Result<Wallet, TxError> {
+        if seconds_ahead < 0.0 {
+            return Err(TxError::NegativeHorizon);
+        }
+        let mut w = self.wallets[i].clone();
+        if i == 0 || w.contract {
+            return Ok(w);
+        }
+        if !(self.cfg.prate.max(self.cfg.rate * w.rate_multiplier) * seconds_ahead / SPY)
+            .exp()
+            .is_finite()
+        {
+            return Err(TxError::HorizonTooLarge);
+        }
+        let t = self.clock.now() + seconds_ahead;
+        let koi = self.wallets[0].balance;
+        let erate = self.cfg.erate(koi, t - self.genesis);
+        let (vested, interest) = w.accrual(&self.cfg, erate, t);
+        w.credit(vested, interest.min(koi.max(Money::ZERO)), t);
+        Ok(w)
+    }
+
+    /// Interest wallet `i` would have earned from `since` until now had it
+    /// held today's balance and vested funds throughout, at today's
+    /// emission rate. Nothing is credited.
+    fn interest_since(&self, i: usize, since: f64) -> Result<Money, TxError> {
+        if i == 0 || self.wallets[i].contract {
+            return Err(TxError::NoInterest);
+        }
+        let t = self.clock.now();
+        if since > t {
+            return Err(TxError::FutureSince);
+        }
+        let koi = self.wallets[0].balance;
+        let erate = self.cfg.erate(koi, t - self.genesis);
+        let mut w = self.project(i, 0.0)?;
+        if !(erate * w.rate_multiplier * (t - since) / SPY)
+            .exp()
+            .is_finite()
+        {
+            return Err(TxError::HorizonTooLarge);
+        }
+        // Nothing left locked, so only the interest formula applies
+        w.locked = Money::ZERO;
+        w.t = since;
+        let (_, interest) = w.accrual(&self.cfg, erate, t);
+        Ok(interest.min(koi.max(Money::ZERO)))
+    }
+
+    /// Credit wallet `i`'s interest and vesting now and, with `claim`, move
+    /// its vested funds to its balance as a zero-amount self-send would.
+    fn settle_now(&mut self, i: usize, claim: bool) -> Result<(), TxError> {
+        if i == 0 || self.wallets[i].contract {
+            return Err(TxError::NoInterest);
+        }
+        if claim {
+            self.early_settle(i, Money::ZERO)?;
+        } else {
+            self.settle(i);
+            self.touch();
+        }
+        Ok(())
+    }
+
+    fn early_settle(&mut self, i: usize, amount: Money) -> Result<Option<u64>, TxError> {
+        if i == 0 {
+            return Err(TxError::KoiCannotSettle);
+        }
+        if amount < Money::ZERO {
+            return Err(TxError::NegativeAmount);
+        }
+
+        self.settle(i);
+
+        if amount > Money::ZERO {
+            let available = self.wallets[i].locked * self.cfg.early_available;
+            if amount > available {
+                return Err(TxError::ExceedsAvailable);
+            }
+        }
+
+        // Move all vested to balance (free), reset interest counter
+        let claimed = self.wallets[i].vested;
+        self.wallets[i].balance += claimed;
+        self.wallets[i].vested = Money::ZERO;
+        self.wallets[i].interest = Money::ZERO;
+
+        let mut id = None;
+        if claimed > Money::ZERO {
+            id = Some(self.push_log(i, i, claimed, Money::ZERO, TxKind::Settle));
+            self.wallets[i].tx_count += 1;
+        }
+
+        // Early settlement: wallet gets amount, fee goes to Koi
+        if amount > Money::ZERO {
+            // Never more than is left locked, whatever rounding did
+            let fee = (amount * self.cfg.early_fee).min(self.wallets[i].locked - amount);
+            self.wallets[i].balance += amount;
+            self.wallets[i].locked -= amount + fee;
+            self.wallets[0].balance += fee;
+            self.fees_collected += fee;
+            id = Some(self.push_log(i, i, amount, fee, TxKind::EarlySettle));
+            self.wallets[i].tx_count += 1;
+        }
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+
+        self.touch();
+        Ok(id)
+    }
+
+    /// Transfer `amount` from one wallet to another, returning the id of the
+    /// logged transaction (a self-send that claims nothing logs none).
+    /// Wallet-to-wallet sends below `min_tx_amount` are refused as dust.
+    fn send(&mut self, from: usize, to: usize, amount: Money) -> Result<Option<u64>, TxError> {
+        let dust = Money::ZERO < amount && amount < self.cfg.min_tx_amount;
+        if dust && from != to && from != 0 && to != 0 {
+            return Err(TxError::BelowMinimum);
+        }
+        let kind = if from == 0 {
+            TxKind::Gift
+        } else {
+            TxKind::Transfer
+        };
+        self.transfer(from, to, amount, kind)
+    }
+
+    fn transfer(
+        &mut self,
+        from: usize,
+        to: usize,
+        amount: Money,
+        kind: TxKind,
+    ) -> Result<Option<u64>, TxError> {
+        if from == STAKING_IDX {
+            return Err(TxError::StakedFunds);
+        }
+        if self.wallets[from].frozen || self.wallets[to].frozen {
+            return Err(TxError::Frozen);
+        }
+        if from == to {
+            return self.early_settle(from, amount);
+        }
+        if amount <= Money::ZERO {
+            return Err(TxError::NonPositiveAmount);
+        }
+
+        // Both settle up front so neither sees the transfer half-applied
+        self.settle(from);
+        self.settle(to);
+
+        if self.wallets[from].balance < amount {
+            return Err(TxError::InsufficientBalance);
+        }
+        // Contracts pay out most of their balance by design, so skip the tiers
+        let fee_rate = if self.wallets[from].contract {
+            self.cfg.fee_rate
+        } else {
+            self.cfg.fee_rate_for(amount, self.wallets[from].balance)
+        };
+        // Gifts and payouts come from Koi's and contracts' own rules, so
+        // only plain transfers can be taken back
+        let touched = [from, to, 0];
+        let before: Option<Vec<(usize, Wallet)>> = (kind == TxKind::Transfer).then(|| {
+            let distinct = touched
+                .iter()
+                .enumerate()
+                .filter(|&(k, i)| !touched[..k].contains(i));
+            distinct
+                .map(|(_, &i)| (i, self.wallets[i].clone()))
+                .collect()
+        });
+        let first_id = self.next_id;
+
+        self.wallets[from].balance -= amount;
+        self.wallets[from].sent += amount;
+
+        // Fee on wallet-to-wallet (not involving Koi), steeper for big sends
+        let mut send_amount = amount;
+        let fee = if from != 0 && to != 0 {
+            let mut rem = amount * fee_rate;
+
+            // Source fee: locked first
+            let fl = rem.min(self.wallets[from].locked);
+            self.wallets[from].locked -= fl;
+            rem -= fl;
+
+            // Then vested
+            let fv = rem.min(self.wallets[from].vested);
+            self.wallets[from].vested -= fv;
+            rem -= fv;
+
+            // Then excess balance (already deducted amount)
+            let fb = rem.min(self.wallets[from].balance);
+            self.wallets[from].balance -= fb;
+            rem -= fb;
+
+            // Whatever the sender's other funds couldn't cover comes out of
+            // the transfer itself, so the fee is what left the sender in total
+            let rem = rem.min(send_amount);
+            send_amount -= rem;
+            let fee = fl + fv + fb + rem;
+
+            self.wallets[0].balance += fee;
+            self.fees_collected += fee;
+            fee
+        } else {
+            Money::ZERO
+        };
+
+        let contract = self.contracts.iter().position(|c| c.idx == to);
+        if let Some(k) = contract {
+            self.contracts[k].contributions[from] += send_amount;
+        }
+        if to == STAKING_IDX {
+            self.stakes.push(Stake {
+                wallet: from,
+                amount: send_amount,
+                t: self.clock.now(),
+            });
+        }
+
+        if to == 0 || self.wallets[to].contract {
+            self.wallets[to].balance += send_amount;
+        } else {
+            // Round the liquid share down; the locked share takes the remainder
+            let liquid = send_amount * self.cfg.liquid_share;
+            self.wallets[to].balance += liquid;
+            self.wallets[to].locked += send_amount - liquid;
+            if self.cfg.vesting_cliff > 0.0 {
+                self.wallets[to].cliff.push(Tranche {
+                    amount: send_amount - liquid,
+                    t: self.clock.now(),
+                });
+            }
+        }
+        self.wallets[to].received += send_amount;
+        self.wallets[from].tx_count += 1;
+        self.wallets[to].tx_count += 1;
+
+        let id = self.push_log(from, to, send_amount, fee, kind);
+
+        // Only the first transfer into a wallet refers it, whether or not
+        // the bonus was on at the time
+        let wallets = (&self.wallets[from], &self.wallets[to]);
+        if kind == TxKind::Transfer && !wallets.0.contract && !wallets.1.contract {
+            if !wallets.1.referred && self.cfg.referral {
+                self.pay_referral(from);
+            }
+            self.wallets[to].referred = true;
+        }
+        self.undo = before.map(|before| Undo {
+            ids: first_id..self.next_id,
+            wallets: before
+                .into_iter()
+                .map(|(i, w)| (i, w, self.wallets[i].clone()))
+                .collect(),
+            fee,
+            contribution: contract.map(|k| (k, from, send_amount)),
+            stake: to == STAKING_IDX,
+        });
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+
+        self.touch();
+        Ok(Some(id))
+    }
+
+    /// Reverse the latest transaction, if it was a wallet-to-wallet transfer
+    /// with nothing logged since, returning the id it had. Its entries leave
+    /// the log but stay in the journal, and an `undo` entry records that it
+    /// was reversed. Ids aren't reused.
+    fn undo(&mut self) -> Result<u64, TxError> {
+        let u = self
+            .undo
+            .take()
+            .filter(|u| u.ids.end == self.next_id)
+            .ok_or(TxError::NothingToUndo)?;
+        // Each wallet gives back what the transfer changed, which the
+        // recipient can't if it has since spent or vested it
+        let reverted: Vec<Wallet> = u
+            .wallets
+            .iter()
+            .map(|(i, before, after)| {
+                let mut w = self.wallets[*i].clone();
+                w.balance += before.balance - after.balance;
+                w.locked += before.locked - after.locked;
+                w.vested += before.vested - after.vested;
+                w.sent += before.sent - after.sent;
+                w.received += before.received - after.received;
+                w.tx_count -= after.tx_count - before.tx_count;
+                w.referred = before.referred;
+                if after.cliff.len() > before.cliff.len() {
+                    if let Some(k) = w
+                        .cliff
+                        .iter()
+                        .rposition(|tr| after.cliff.last() == Some(tr))
+                    {
+                        w.cliff.remove(k);
+                    }
+                }
+                w
+            })
+            .collect();
+        if reverted
+            .iter()
+            .any(|w| w.balance < Money::ZERO || w.locked < Money::ZERO || w.vested < Money::ZERO)
+        {
+            return Err(TxError::CannotUndo);
+        }
+        for ((i, _, _), w) in u.wallets.iter().zip(reverted) {
+            self.wallets[*i] = w;
+        }
+        if let Some((k, from, weight)) = u.contribution {
+            self.contracts[k].contributions[from] -= weight;
+        }
+        if u.stake {
+            self.stakes.pop();
+        }
+        self.fees_collected -= u.fee;
+        let transfer = self.log.iter().find(|e| e.id == u.ids.start).cloned();
+        self.log.retain(|e| !u.ids.contains(&e.id));
+        if let Some(e) = transfer {
+            let (from, to) = (self.name_to_idx[&e.from], self.name_to_idx[&e.to]);
+            self.push_log(from, to, e.amount, e.fee, TxKind::Undo);
+        }
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+
+        // Subscribers already have the entries, so they need a fresh snapshot
+        self.epoch += 1;
+        self.touch();
+        Ok(u.ids.start)
+    }
+
+    /// Pay the referral bonus to wallet `i`, as much of it as Koi holds.
+    fn pay_referral(&mut self, i: usize) {
+        let bonus = self
+            .cfg
+            .referral_bonus
+            .min(self.wallets[0].balance.max(Money::ZERO));
+        if bonus <= Money::ZERO {
+            return;
+        }
+        self.wallets[0].balance -= bonus;
+        self.wallets[i].balance += bonus;
+        self.wallets[i].tx_count += 1;
+        self.push_log(0, i, bonus, Money::ZERO, TxKind::Referral);
+    }
+
+    /// The configured supply plus everything minted since.
+    fn total_supply(&self) -> Money {
+        self.cfg.supply + self.minted
+    }
+
+    /// Compare everything held, plus what was burned, against the supply.
+    /// Amounts are fixed-point, so any drift at all is a bug.
+    fn invariant(&self) -> Invariant {
+        let held: Money = self
+            .wallets
+            .iter()
+            .map(|w| w.balance + w.locked + w.vested)
+            .sum();
+        let total = held + self.burned;
+        Invariant {
+            ok: total == self.total_supply(),
+            total,
+            drift: total - self.total_supply(),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_invariant(&self) {
+        let inv = self.invariant();
+        assert!(inv.ok, "supply drifted by {:?}", inv.drift);
+    }
+
+    /// Destroy `amount` of wallet `i`'s balance. Unlike a fee nothing goes
+    /// to Koi, so the supply shrinks.
+    fn burn(&mut self, i: usize, amount: Money) -> Result<u64, TxError> {
+        if i == STAKING_IDX {
+            return Err(TxError::StakedFunds);
+        }
+        if self.wallets[i].frozen {
+            return Err(TxError::Frozen);
+        }
+        if amount <= Money::ZERO {
+            return Err(TxError::NonPositiveAmount);
+        }
+        self.settle(i);
+        if self.wallets[i].balance < amount {
+            return Err(TxError::InsufficientBalance);
+        }
+        self.wallets[i].balance -= amount;
+        self.wallets[i].sent += amount;
+        self.wallets[i].tx_count += 1;
+        self.burned += amount;
+        let id = self.push_log(i, i, amount, Money::ZERO, TxKind::Burn);
+
+        self.touch();
+        Ok(id)
+    }
+
+    /// Create `amount` of new supply in Koi's balance, so emission can go
+    /// on once interest has drained it. Emission stays relative to the
+    /// configured supply, so minting raises the rate as Koi refills. The
+    /// total supply never passes `MAX_SUPPLY`, so no sum of holdings can
+    /// overflow.
+    fn mint(&mut self, amount: Money) -> Result<u64, TxError> {
+        if amount <= Money::ZERO {
+            return Err(TxError::NonPositiveAmount);
+        }
+        if amount > MAX_SUPPLY - self.total_supply() {
+            return Err(TxError::SupplyCap);
+        }
+        self.wallets[0].balance += amount;
+        self.wallets[0].received += amount;
+        self.wallets[0].tx_count += 1;
+        self.minted += amount;
+        let id = self.push_log(0, 0, amount, Money::ZERO, TxKind::Mint);
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+
+        self.touch();
+        Ok(id)
+    }
+
+    /// Return all of wallet `i`'s stakes to its balance, with a reward
+    /// compounding at `stake_rate` for as long as each was staked. Koi
+    /// funds the reward, up to what it still holds.
+    fn unstake(&mut self, i: usize) -> Result<u64, TxError> {
+        if self.wallets[i].frozen {
+            return Err(TxError::Frozen);
+        }
+        let (mine, rest): (Vec<Stake>, Vec<Stake>) = std::mem::take(&mut self.stakes)
+            .into_iter()
+            .partition(|s| s.wallet == i);
+        self.stakes = rest;
+        if mine.is_empty() {
+            return Err(TxError::NothingStaked);
+        }
+
+        // Settle first, so the reward is capped by what Koi has left after
+        // paying this wallet's interest
+        self.settle(i);
+        let t = self.clock.now();
+        let principal: Money = mine.iter().map(|s| s.amount).sum();
+        let reward: f64 = mine
+            .iter()
+            .map(|s| {
+                s.amount.to_f64() * ((self.cfg.stake_rate * (t - s.t).max(0.0) / SPY).exp() - 1.0)
+            })
+            .sum();
+        let reward = Money::from_f64(reward).min(self.wallets[0].balance.max(Money::ZERO));
+
+        self.wallets[STAKING_IDX].balance -= principal;
+        self.wallets[0].balance -= reward;
+        self.wallets[i].balance += principal + reward;
+        self.wallets[i].received += principal + reward;
+        self.wallets[i].tx_count += 1;
+        self.wallets[STAKING_IDX].tx_count += 1;
+        let id = self.push_log(
+            STAKING_IDX,
+            i,
+            principal + reward,
+            Money::ZERO,
+            TxKind::Unstake,
+        );
+        #[cfg(debug_assertions)]
+        self.check_invariant();
+
+        self.touch();
+        Ok(id)
+    }
+
+    fn push_log(&mut self, from: usize, to: usize, amount: Money, fee: Money, kind: TxKind) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let tx = TxLog {
+            id,
+            from: self.wallets[from].name.clone(),
+            to: self.wallets[to].name.clone(),
+            amount,
+            fee,
+            kind,
+            t: self.clock.now(),
+        };
+        if let Some(held) = &mut self.held {
+            held.push(tx.clone());
+        } else {
+            self.commit(&tx);
+        }
+        if self.log.len() >= self.cfg.log_cap {
+            self.log.pop_front();
+        }
+        self.log.push_back(tx);
+        id
+    }
+
+    /// Trace and journal `tx`, which now stands. Entries of a batch or dry
+    /// run wait in `held` until it's known they do.
+    fn commit(&self, tx: &TxLog) {
+        tracing::info!(
+            id = tx.id,
+            from = %tx.from,
+            to = %tx.to,
+            amount = tx.amount.to_f64(),
+            fee = tx.fee.to_f64(),
+            kind = ?tx.kind,
+            "transaction"
+        );
+        if let Some(journal) = &self.journal {
+            let _ = journal.send(Journal::Entry(tx.clone()));
+        }
+    }
+
+    /// Apply `transfers` in order, all or nothing. On failure every change is
+    /// rolled back and the index of the failing transfer is returned.
+    fn batch(
+        &mut self,
+        transfers: &[(usize, usize, Money)],
+    ) -> Result<Vec<Option<u64>>, (usize, TxError)> {
+        let checkpoint = self.persisted();
+        let undo = self.undo.take();
+        self.held = Some(Vec::new());
+        let mut ids = Vec::with_capacity(transfers.len());
+        for (k, &(from, to, amount)) in transfers.iter().enumerate() {
+            match self.send(from, to, amount) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    // Nothing happened, so the last transfer stays undoable
+                    self.held = None;
+                    self.restore(checkpoint);
+                    self.undo = undo;
+                    self.touch();
+                    return Err((k, e));
+                }
+            }
+        }
+        for tx in self.held.take().unwrap_or_default() {
+            self.commit(&tx);
+        }
+        // Undoing the last transfer alone would break the all or nothing
+        self.undo = None;
+        Ok(ids)
+    }
+
+    /// Gift `total` from Koi across `weights` in proportion, all or nothing.
+    /// Shares round down and the last recipient takes the remainder, so
+    /// exactly `total` goes out. Returns each recipient's share and its id.
+    fn airdrop(
+        &mut self,
+        total: Money,
+        weights: &[(usize, f64)],
+    ) -> Result<Vec<(usize, Money, Option<u64>)>, TxError> {
+        if total <= Money::ZERO {
+            return Err(TxError::NonPositiveAmount);
+        }
+        let sum: f64 = weights.iter().map(|&(_, w)| w).sum();
+        let valid = |&(i, w): &(usize, f64)| i != 0 && !self.wallets[i].contract && w >= 0.0;
+        if !(weights.iter().all(valid) && sum > 0.0 && sum.is_finite()) {
+            return Err(TxError::InvalidAirdrop);
+        }
+        let recipients: Vec<(usize, f64)> =
+            weights.iter().copied().filter(|&(_, w)| w > 0.0).collect();
+        let mut left = total;
+        let mut transfers: Vec<(usize, usize, Money)> = recipients
+            .iter()
+            .map(|&(i, w)| {
+                let share = total * (w / sum);
+                left -= share;
+                (0, i, share)
+            })
+            .collect();
+        if let Some(last) = transfers.last_mut() {
+            last.2 += left;
+        }
+        let ids = self.batch(&transfers).map_err(|(_, e)| e)?;
+        Ok(transfers
+            .iter()
+            .zip(ids)
+            .map(|(&(_, i, share), id)| (i, share, id))
+            .collect())
+    }
+
+    /// Who a draw on contract `k` would pick from, weighted by contribution,
+    /// or None if it can't run yet for lack of contributors.
+    fn draw_weights(&self, k: usize) -> Option<Vec<(usize, f64)>> {
+        let mut weights: Vec<(usize, f64)> = self.contracts[k]
+            .contributions
+            .iter()
+            .enumerate()
+            .filter(|&(i, &c)| c > Money::ZERO && !self.wallets[i].frozen)
+            .map(|(i, &c)| (i, c.to_f64()))
+            .collect();
+        if weights.is_empty() || weights.len() < self.cfg.min_contributors {
+            return None;
+        }
+        if self.cfg.exclude_top_contributor {
+            // The largest contributor sits the draw out; if nobody else
+            // contributed, the prize rolls over
+            let top = (0..weights.len()).max_by(|&a, &b| weights[a].1.total_cmp(&weights[b].1));
+            weights.swap_remove(top.unwrap());
+            if weights.is_empty() {
+                return None;
+            }
+        }
+        Some(weights)
+    }
+
+    /// Each contract's contributors and their odds in its next draw.
+    fn odds(&self) -> Vec<ContractOdds> {
+        (0..self.contracts.len())
+            .map(|k| {
+                let c = &self.contracts[k];
+                let weights = self.draw_weights(k).unwrap_or_default();
+                let eligible: f64 = weights.iter().map(|&(_, w)| w).sum();
+                let contributors = c
+                    .contributions
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &m)| m > Money::ZERO)
+                    .map(|(i, &m)| Odds {
+                        name: self.wallets[i].name.clone(),
+                        contribution: m,
+                        probability: weights
+                            .iter()
+                            .find(|&&(j, _)| j == i)
+                            .map_or(0.0, |&(_, w)| w / eligible),
+                    })
+                    .collect();
+                ContractOdds {
+                    contract: self.wallets[c.idx].name.clone(),
+                    balance: self.wallets[c.idx].balance,
+                    threshold: c.threshold,
+                    total: c.contributions.iter().copied().sum(),
+                    contributors,
+                }
+            })
+            .collect()
+    }
+
+    /// The outcome of logged send `id` from `from` to `to`, as it stands.
+    /// `first` is the first id the send could have logged, so a self-send
+    /// can report what it claimed alongside any early withdrawal.
+    fn send_res(&self, first: u64, id: Option<u64>, from: usize, to: usize) -> SendRes {
+        let entry = id.and_then(|id| self.log.iter().rev().find(|e| e.id == id));
+        let claimed = (from == to).then(|| {
+            self.log
+                .iter()
+                .rev()
+                .take_while(|e| e.id >= first)
+                .filter(|e| e.kind == TxKind::Settle && e.from == self.wallets[from].name)
+                .map(|e| e.amount)
+                .sum()
+        });
+        // A self-send that had nothing to claim logs nothing, but settled
+        let kind = entry
+            .map(|e| e.kind)
+            .or((from == to).then_some(TxKind::Settle));
+        SendRes {
+            id,
+            kind,
+            delivered: entry.map(|e| e.amount),
+            fee: entry.map(|e| e.fee),
+            claimed,
+            from_balance: Some(self.wallets[from].balance),
+            to_balance: Some(self.wallets[to].balance),
+            ..SendRes::ok()
+        }
+    }
+
+    /// What sending `amount` from `from` to `to` would do, without doing
+    /// it: the state, undo record and journal are restored afterwards and
+    /// subscribers never hear of it.
+    fn preview(&mut self, from: usize, to: usize, amount: Money) -> Result<SendRes, TxError> {
+        let checkpoint = self.persisted();
+        let undo = self.undo.take();
+        let dirty = std::mem::take(&mut self.dirty);
+        self.held = Some(Vec::new());
+        let first = self.next_id;
+        let res = self.send(from, to, amount).map(|id| SendRes {
+            id: None,
+            ..self.send_res(first, id, from, to)
+        });
+        self.held = None;
+        self.restore(checkpoint);
+        self.undo = undo;
+        self.dirty = dirty;
+        res
+    }
+
+    /// Pay out every contract whose balance has passed its threshold,
+    /// unless it already paid out within the cooldown or too few distinct
+    /// wallets have contributed.
+    fn check_contracts(&mut self) {
+        let t = self.clock.now();
+        for k in 0..self.contracts.len() {
+            let Contract {
+                idx,
+                threshold,
+                share,
+                last_payout_t,
+                ..
+            } = self.contracts[k];
+            if self.wallets[idx].balance <= threshold
+                || t - last_payout_t < self.cfg.payout_cooldown
+            {
+                continue;
+            }
+            let Some(mut weights) = self.draw_weights(k) else {
+                continue;
+            };
+            // Draw a distinct winner per tier; tiers without a contributor
+            // left to fill roll over with the rest of the balance
+            let pool = self.wallets[idx].balance * share;
+            for j in 0..self.cfg.prize_split.len().min(weights.len()) {
+                let dist = WeightedIndex::new(weights.iter().map(|&(_, w)| w)).unwrap();
+                let (winner, _) = weights.swap_remove(dist.sample(&mut self.rng));
+                // Earlier tiers' fees came out of the balance too
+                let payout = (pool * self.cfg.prize_split[j]).min(self.wallets[idx].balance);
+                if self
+                    .transfer(idx, winner, payout, TxKind::MillionairePayout)
+                    .is_ok()
+                {
+                    // What arrived, less any fee the balance couldn't cover
+                    let won = self.log.back().map_or(payout, |e| e.amount);
+                    tracing::info!(
+                        contract = %self.wallets[idx].name,
+                        winner = %self.wallets[winner].name,
+                        amount = won.to_f64(),
+                        "millionaire payout"
+                    );
+                    if let Some(webhook) = &self.webhook {
+                        let _ = webhook.send(Payout {
+                            winner: self.wallets[winner].name.clone(),
+                            amount: won,
+                            contract: self.wallets[idx].name.clone(),
+                            t,
+                        });
+                    }
+                }
+            }
+            self.contracts[k].last_payout_t = t;
+            // Decay contributions, to zero by default
+            let decay = self.cfg.contribution_decay;
+            for c in self.contracts[k].contributions.iter_mut() {
+                *c = *c * decay;
+            }
+        }
+    }
+
+    /// Rebuild the economy as `new` would, keeping every wallet's name,
+    /// subscribers, the journal, the webhook and the pause state. Transaction
+    /// ids carry on from before, so none is ever reused.
+    fn reset(&mut self) {
+        let names = self.wallets.iter().map(|w| (w.name.clone(), w.contract));
+        // Gifts go out on a private channel so subscribers see one
+        // notification for the whole reset rather than lagging on hundreds
+        let mut fresh = App::new_from(
+            self.next_id,
+            self.cfg.clone(),
+            names.collect(),
+            broadcast::channel(1).0,
+            self.journal.clone(),
+            Some(self.rng.gen()),
+            self.clock.clone(),
+        );
+        // Keys stay with whoever holds them, and nonces carry on so signed
+        // requests from before can't be replayed
+        for w in &mut fresh.wallets {
+            if let Some(i) = self.index_of(&w.name) {
+                w.pubkey = std::mem::take(&mut self.wallets[i].pubkey);
+                w.nonce = self.wallets[i].nonce;
+            }
+        }
+        fresh.notify = self.notify.clone();
+        fresh.dirty = self.dirty.clone();
+        fresh.webhook = self.webhook.clone();
+        fresh.lag = self.lag.clone();
+        fresh.sim_paused = self.sim_paused;
+        fresh.epoch = self.epoch + 1;
+        *self = fresh;
+        self.touch();
+    }
+
+    /// The response first given for `key`, if it hasn't expired.
+    fn recall(&mut self, key: &str) -> Option<SendRes> {
+        let cutoff = self.clock.now() - IDEMPOTENCY_TTL;
+        while let Some((t, _)) = self.idempotency_order.front() {
+            if *t >= cutoff && self.idempotency_order.len() <= IDEMPOTENCY_CAP {
+                break;
+            }
+            if let Some((_, old)) = self.idempotency_order.pop_front() {
+                self.idempotency.remove(&old);
+            }
+        }
+        self.idempotency.get(key).cloned()
+    }
+
+    fn remember(&mut self, key: String, res: SendRes) {
+        self.idempotency_order
+            .push_back((self.clock.now(), key.clone()));
+        self.idempotency.insert(key, res);
+    }
+
+    /// Apply one `/api/send` request: replayed keys get their first
+    /// response back, dry runs are previewed, anything else is sent.
+    fn submit(&mut self, req: SendReq) -> Result<SendRes, TxError> {
+        let replay = req.idempotency_key.as_deref().filter(|_| !req.dry_run);
+        if let Some(res) = replay.and_then(|k| self.recall(k)) {
+            return Ok(res);
+        }
+        let (Some(f), Some(t)) = (self.index_of(&req.from), self.index_of(&req.to)) else {
+            return Err(TxError::UnknownWallet);
+        };
+        self.verify(f, &req, self.wallets[f].nonce)?;
+        if req.dry_run {
+            return self.preview(f, t, req.amount);
+        }
+        let first = self.next_id;
+        let id = self.send(f, t, req.amount)?;
+        self.wallets[f].nonce = req.nonce;
+        self.check_contracts();
+        let res = self.send_res(first, id, f, t);
+        if let Some(key) = req.idempotency_key {
+            self.remember(key, res.clone());
+        }
+        Ok(res)
+    }
+
+    /// Apply one `/api/send/batch` request, every transfer or none. A
+    /// failure names the transfer at fault.
+    fn submit_batch(&mut self, reqs: &[SendReq]) -> Result<Vec<Option<u64>>, (usize, TxError)> {
+        let mut transfers = Vec::with_capacity(reqs.len());
+        // Nonces as they'll stand once the transfers before each one apply
+        let mut nonces = HashMap::new();
+        for (k, r) in reqs.iter().enumerate() {
+            let (Some(f), Some(t)) = (self.index_of(&r.from), self.index_of(&r.to)) else {
+                return Err((k, TxError::UnknownWallet));
+            };
+            let nonce = nonces.entry(f).or_insert(self.wallets[f].nonce);
+            self.verify(f, r, *nonce).map_err(|e| (k, e))?;
+            *nonce = r.nonce;
+            transfers.push((f, t, r.amount));
+        }
+        let ids = self.batch(&transfers)?;
+        for (f, nonce) in nonces {
+            self.wallets[f].nonce = nonce;
+        }
+        self.check_contracts();
+        Ok(ids)
+    }
+
+    /// One tick of the background simulation: a random hot wallet sends a
+    /// `shares` fraction of its balance to a contract or to another wallet.
+    fn simulate(&mut self, shares: std::ops::RangeInclusive<f64>) {
+        if self.sim_paused {
+            return;
+        }
+        let n = self.hot();
+        // Pick from excluding Koi (0), Alice (1), and contracts
+        let from = loop {
+            let f = self.rng.gen_range(2..n);
+            if !self.wallets[f].contract {
+                break f;
+            }
+        };
+        // 50% chance target is a contract, otherwise random wallet
+        let to = if self.rng.gen_bool(0.5) {
+            let hot: Vec<usize> = self
+                .contracts
+                .iter()
+                .map(|c| c.idx)
+                .filter(|&i| i < n)
+                .collect();
+            hot[self.rng.gen_range(0..hot.len())]
+        } else {
+            loop {
+                let t = self.rng.gen_range(2..n);
+                if t != from && !self.wallets[t].contract {
+                    break t;
+                }
+            }
+        };
+        let pct = self.rng.gen_range(shares);
+        let bal = self.wallets[from].balance;
+        if bal > Money::ZERO {
+            let amount = Money::from_f64(bal.to_f64() * pct);
+            if amount >= self.cfg.min_tx_amount {
+                let _ = self.send(from, to, amount);
+                self.check_contracts();
+            }
+        }
+    }
+
+    fn wallet_res(&self, i: usize) -> WalletRes {
+        let wallet = self.wallets[i].clone();
+        WalletRes {
+            next_nonce: wallet.nonce + 1,
+            apy: self.apy(i),
+            wallet,
+        }
+    }
+
+    /// Annual yield wallet `i` earns at Koi's current balance. Koi and
+    /// contracts earn nothing.
+    fn apy(&self, i: usize) -> f64 {
+        let w = &self.wallets[i];
+        if i == 0 || w.contract {
+            return 0.0;
+        }
+        let age = self.clock.now() - self.genesis;
+        let erate = self.cfg.erate(self.wallets[0].balance, age);
+        (erate * w.rate_multiplier).exp_m1()
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.sim_paused = paused;
+        self.touch();
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        // Only the hot set is projected. Cold wallets' accrual reaches Koi
+        // as they settle
+        let t = self.clock.now();
+        let wallets = self.projected(self.hot());
+        let koi = wallets[0].balance;
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            wallets,
+            wallet_count: self.wallets.len(),
+            log: self.log.iter().cloned().collect(),
+            rate: self.cfg.rate_at(t - self.genesis),
+            apy: self.cfg.erate(koi, t - self.genesis).exp_m1(),
+            next_halving: (self.cfg.halving_interval > 0.0).then(|| {
+                self.genesis
+                    + (self.cfg.halvings(t - self.genesis) + 1.0) * self.cfg.halving_interval
+            }),
+            genesis: self.genesis,
+            age_seconds: t - self.genesis,
+            prate: self.cfg.prate,
+            spy: SPY,
+            supply: self.cfg.supply.to_f64(),
+            k0: (self.cfg.supply - self.cfg.gift_alice - self.cfg.gift_rest).to_f64(),
+            burned: self.burned,
+            minted: self.minted,
+            fees_collected: self.fees_collected,
+            config: self.cfg.clone(),
+            contracts: (0..self.contracts.len())
+                .map(|k| self.contract_info(k))
+                .collect(),
+            paused: self.sim_paused,
+            t,
+        }
+    }
+
+    fn contract_info(&self, k: usize) -> ContractInfo {
+        let c = &self.contracts[k];
+        ContractInfo {
+            name: self.wallets[c.idx].name.clone(),
+            threshold: c.threshold,
+            share: c.share,
+            last_payout_t: c.last_payout_t,
+        }
+    }
+
+    /// Change when lottery contract `i` pays out and what share of its
+    /// balance goes. The payout cooldown still applies, so a lowered
+    /// threshold can't set off back-to-back draws.
+    fn configure_contract(
+        &mut self,
+        i: usize,
+        threshold: Option<Money>,
+        share: Option<f64>,
+    ) -> Result<ContractInfo, TxError> {
+        let k = self
+            .contracts
+            .iter()
+            .position(|c| c.idx == i)
+            .ok_or(TxError::NotAContract)?;
+        let threshold = threshold.unwrap_or(self.contracts[k].threshold);
+        let share = share.unwrap_or(self.contracts[k].share);
+        if threshold <= Money::ZERO || !(share > 0.0 && share <= 1.0) {
+            return Err(TxError::InvalidContractConfig);
+        }
+        self.contracts[k].threshold = threshold;
+        self.contracts[k].share = share;
+        // Rules only travel in snapshots
+        self.epoch += 1;
+        self.touch();
+        Ok(self.contract_info(k))
+    }
+
+    /// The first `n` wallets as settling them now would leave them, without
+    /// mutating anything. What they earn is charged to Koi, wallet 0, while
+    /// it lasts.
+    fn projected(&self, n: usize) -> Vec<Wallet> {
+        let t = self.clock.now();
+        let koi = self.wallets[0].balance;
+        let mut owed = Money::ZERO;
+        let mut wallets = self.wallets[..n].to_vec();
+        let erate = self.cfg.erate(koi, t - self.genesis);
+        for w in wallets.iter_mut().skip(1).filter(|w| !w.contract) {
+            let (vested, interest) = w.accrual(&self.cfg, erate, t);
+            let interest = interest.min((koi - owed).max(Money::ZERO));
+            w.credit(vested, interest, t);
+            owed += interest;
+        }
+        wallets[0].balance -= owed;
+        wallets
+    }
+
+    /// Holdings and distribution, projected to now.
+    fn stats(&self) -> Stats {
+        let wallets = self.projected(self.wallets.len());
+        let koi = wallets[0].balance;
+        let holders = &wallets[1..];
+        let mut held: Vec<Money> = holders
+            .iter()
+            .filter(|w| !w.contract)
+            .map(|w| w.balance + w.vested + w.locked)
+            .collect();
+        held.sort();
+        let n = held.len();
+        let median = match n {
+            0 => Money::ZERO,
+            _ if n % 2 == 1 => held[n / 2],
+            _ => (held[n / 2 - 1] + held[n / 2]) / 2,
+        };
+        Stats {
+            koi_balance: koi,
+            total_locked: holders.iter().map(|w| w.locked).sum(),
+            total_vested: holders.iter().map(|w| w.vested).sum(),
+            total_circulating: holders.iter().map(|w| w.balance).sum(),
+            active_wallets: holders.iter().filter(|w| w.balance > Money::ZERO).count(),
+            fees_collected: self.fees_collected,
+            transactions: self.next_id,
+            emission_rate: self.cfg.erate(koi, self.clock.now() - self.genesis),
+            gini: gini(&held),
+            min_holdings: held.first().copied().unwrap_or(Money::ZERO),
+            median_holdings: median,
+            max_holdings: held.last().copied().unwrap_or(Money::ZERO),
+        }
+    }
+
+    /// Settle the wallets outside the hot set, which nothing else keeps
+    /// current, so what they've earned is charged to Koi.
+    fn settle_cold(&mut self) {
+        let cold = self.hot()..self.wallets.len();
+        if cold.is_empty() {
+            return;
+        }
+        for i in cold {
+            self.settle(i);
+        }
+        self.touch();
+    }
+
+    /// Break the supply down by where it sits, projected to now.
+    fn supply(&self) -> Supply {
+        let wallets = self.projected(self.wallets.len());
+        let holders = &wallets[1..];
+        let locked = holders.iter().map(|w| w.locked).sum();
+        let vested = holders.iter().map(|w| w.vested).sum();
+        let liquid = holders.iter().map(|w| w.balance).sum();
+        Supply {
+            total_supply: self.total_supply(),
+            koi_balance: wallets[0].balance,
+            locked,
+            vested,
+            liquid,
+            circulating: locked + vested + liquid,
+            burned: self.burned,
+            minted: self.minted,
+            fees_collected: self.fees_collected,
+        }
+    }
+
+    /// The `n` largest holders, projected to now, by everything they hold. Koi and
+    /// contracts hold on nobody's behalf, so they aren't ranked.
+    fn leaderboard(&self, n: usize) -> Vec<Rank> {
+        let wallets = self.projected(self.wallets.len());
+        let mut holders: Vec<&Wallet> = wallets[1..].iter().filter(|w| !w.contract).collect();
+        holders.sort_by_key(|w| std::cmp::Reverse(w.balance + w.vested + w.locked));
+        holders
+            .into_iter()
+            .take(n)
+            .enumerate()
+            .map(|(k, w)| Rank {
+                rank: k + 1,
+                name: w.name.clone(),
+                holdings: w.balance + w.vested + w.locked,
+                balance: w.balance,
+                vested: w.vested,
+                locked: w.locked,
+            })
+            .collect()
+    }
+
+    fn metrics(&self) -> String {
+        let st = self.stats();
+        let gauges = [
+            (
+                "koi_balance",
+                "Undistributed supply held by Koi",
+                st.koi_balance.to_f64(),
+            ),
+            (
+                "locked_total",
+                "Sum of locked funds across wallets",
+                st.total_locked.to_f64(),
+            ),
+            (
+                "vested_total",
+                "Sum of vested, unclaimed funds across wallets",
+                st.total_vested.to_f64(),
+            ),
+            (
+                "circulating_total",
+                "Sum of non-Koi wallet balances",
+                st.total_circulating.to_f64(),
+            ),
+            ("wallets", "Number of wallets", self.wallets.len() as f64),
+            (
+                "log_length",
+                "Transactions held in the in-memory log",
+                self.log.len() as f64,
+            ),
+        ];
+        let counters = [
+            (
+                "transactions_total",
+                "Transactions processed since start",
+                st.transactions as f64,
+            ),
+            (
+                "fees_collected_total",
+                "Fees paid to Koi since start",
+                st.fees_collected.to_f64(),
+            ),
+            (
+                "broadcast_lag_events_total",
+                "Times a subscriber fell behind the broadcast channel",
+                self.lag.events.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "broadcast_lagged_total",
+                "Updates lagging subscribers skipped",
+                self.lag.skipped.load(Ordering::Relaxed) as f64,
+            ),
+        ];
+        let mut out = String::new();
+        for (kind, metrics) in [("gauge", &gauges[..]), ("counter", &counters[..])] {
+            for (name, help, value) in metrics {
+                out += &format!(
+                    "# HELP crypto_alice_{name} {help}\n\
+                     # TYPE crypto_alice_{name} {kind}\n\
+                     crypto_alice_{name} {value}\n"
+                );
+            }
+        }
+        out
+    }
+
+    /// Send subscribers what changed since the last publish.
+    fn publish(&mut self) {
+        let update = Update {
+            epoch: self.epoch,
+            since: self.published_id,
+            next_id: self.next_id,
+            delta: Shared::new(Frame::Delta(self.delta(&self.published, self.published_id))),
+            snapshot: Shared::new(Frame::Snapshot(Box::new(self.snapshot()))),
+        };
+        self.published.clear();
+        self.published
+            .extend_from_slice(&self.wallets[..self.hot()]);
+        self.published_id = self.next_id;
+        let _ = self.notify.send(update);
+    }
+
+    fn delta(&self, prev: &[Wallet], since: u64) -> Delta {
+        let wallets = self
+            .wallets
+            .iter()
+            .take(self.hot())
+            .enumerate()
+            .filter(|&(i, w)| prev.get(i) != Some(w))
+            .map(|(idx, w)| WalletDelta {
+                idx,
+                wallet: w.clone(),
+            })
+            .collect();
+        let fresh = ((self.next_id - since) as usize).min(self.log.len());
+        Delta {
+            wallets,
+            log: self.log.range(self.log.len() - fresh..).cloned().collect(),
+            paused: self.sim_paused,
+            t: self.clock.now(),
+        }
+    }
+}
+
+/// Handlers look at the state under the read lock. Every change, down to
+/// the settling some reads do first, goes through the queue instead.
+type S = Arc<RwLock<App>>;
+
+/// What the routes share: the state to read, and the queue to change it.
+#[derive(Clone)]
+struct Ctx {
+    state: S,
+    queue: Queue,
+}
+
+impl FromRef<Ctx> for S {
+    fn from_ref(ctx: &Ctx) -> S {
+        ctx.state.clone()
+    }
+}
+
+impl FromRef<Ctx> for Queue {
+    fn from_ref(ctx: &Ctx) -> Queue {
+        ctx.queue.clone()
+    }
+}
+
+/// How the background simulation moves money: one random send every
+/// `interval`, of between `min_share` and `max_share` of the sender's balance.
+struct SimCfg {
+    interval: std::time::Duration,
+    min_share: f64,
+    max_share: f64,
+}
+
+impl SimCfg {
+    /// `SIM_INTERVAL_MS` between sends, each `SIM_MIN_PCT` to `SIM_MAX_PCT`
+    /// percent of the balance.
+    fn from_env() -> Result<Self, String> {
+        let ms: u64 = env_parse("SIM_INTERVAL_MS")?.unwrap_or(1000);
+        let min: f64 = env_parse("SIM_MIN_PCT")?.unwrap_or(0.1);
+        let max: f64 = env_parse("SIM_MAX_PCT")?.unwrap_or(1.0);
+        if ms == 0 {
+            return Err("SIM_INTERVAL_MS must be positive".into());
+        }
+        if !(0.0 < min && min <= max && max <= 100.0) {
+            return Err("need 0 < SIM_MIN_PCT <= SIM_MAX_PCT <= 100".into());
+        }
+        Ok(SimCfg {
+            interval: std::time::Duration::from_millis(ms),
+            min_share: min / 100.0,
+            max_share: max / 100.0,
+        })
+    }
+}
+
+/// Queue one simulation tick every `sim.interval` until the worker stops.
+fn spawn_simulation(queue: Queue, sim: SimCfg) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(sim.interval);
+        // After a slow tick carry on at the usual pace rather than catch up
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let shares = sim.min_share..=sim.max_share;
+        loop {
+            interval.tick().await;
+            let shares = shares.clone();
+            if queue
+                .send(Box::new(move |app| app.simulate(shares)))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    })
+}
+
+/// One scripted send, made `delay_ms` after the step before it.
+#[derive(Deserialize)]
+struct Step {
+    from: String,
+    to: String,
+    amount: Money,
+    #[serde(default)]
+    delay_ms: u64,
+}
+
+/// Queue each of `steps` after its delay, in order.
+fn spawn_scenario(queue: Queue, steps: Vec<Step>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        for (k, step) in steps.into_iter().enumerate() {
+            tokio::time::sleep(std::time::Duration::from_millis(step.delay_ms)).await;
+            let cmd: Command = Box::new(move |app| {
+                let sent = match (app.index_of(&step.from), app.index_of(&step.to)) {
+                    (Some(f), Some(t)) => app.send(f, t, step.amount),
+                    _ => Err(TxError::UnknownWallet),
+                };
+                match sent {
+                    Ok(_) => app.check_contracts(),
+                    Err(e) => tracing::warn!(step = k + 1, error = %e, "scenario step failed"),
+                }
+            });
+            if queue.send(cmd).await.is_err() {
+                return;
+            }
+        }
+        tracing::info!("scenario done");
+    })
+}
+
+/// Add issued secret keys to the JSON object of wallet name to hex secret
+/// at `path`, creating it readable by its owner alone.
+fn save_keys(path: &FsPath, issued: &[(String, SigningKey)]) -> io::Result<()> {
+    let mut secrets: BTreeMap<String, String> = match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e),
+    };
+    for (name, key) in issued {
+        secrets.insert(name.clone(), hex(&key.to_bytes()));
+    }
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    let mut file = opts.open(path)?;
+    file.write_all(&serde_json::to_vec_pretty(&secrets)?)?;
+    file.sync_all()
+}
+
+/// A JSON object of wallet names to gifts in Koi.
+fn load_gifts(path: &FsPath) -> Result<BTreeMap<String, Money>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    serde_json::from_str(&text).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Steps from a JSON array, or from CSV with a `from,to,amount,delay_ms`
+/// header when the file ends in `.csv`.
+fn load_scenario(path: &FsPath) -> Result<Vec<Step>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    if path.extension().is_none_or(|e| e != "csv") {
+        return serde_json::from_str(&text).map_err(|e| format!("{}: {e}", path.display()));
+    }
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty());
+    if lines
+        .next()
+        .is_none_or(|(_, h)| h.trim() != "from,to,amount,delay_ms")
+    {
+        return Err(format!(
+            "{}: expected a from,to,amount,delay_ms header",
+            path.display()
+        ));
+    }
+    lines
+        .map(|(n, line)| {
+            let bad = || format!("{}:{}: invalid step {line:?}", path.display(), n + 1);
+            match line.split(',').map(str::trim).collect::<Vec<_>>()[..] {
+                [from, to, amount, delay_ms] => Ok(Step {
+                    from: from.into(),
+                    to: to.into(),
+                    amount: amount
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|a| a.is_finite())
+                        .map(Money::from_f64)
+                        .ok_or_else(bad)?,
+                    delay_ms: delay_ms.parse().map_err(|_| bad())?,
+                }),
+                _ => Err(bad()),
+            }
+        })
+        .collect()
+}
+
+/// Token buckets per client IP: each refills at `rate` per second up to
+/// `burst`, and a request spends one token.
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: HashMap<IpAddr, (f64, f64)>, // tokens, last refill
+}
+
+impl RateLimiter {
+    /// `SEND_RATE_LIMIT` sends per second per IP, in bursts of up to
+    /// `SEND_BURST`.
+    fn from_env() -> Result<Self, String> {
+        let rate = env_parse("SEND_RATE_LIMIT")?.unwrap_or(5.0);
+        let burst = env_parse("SEND_BURST")?.unwrap_or(20.0);
+        if !(rate > 0.0 && burst >= 1.0) {
+            return Err("SEND_RATE_LIMIT must be positive and SEND_BURST at least 1".into());
+        }
+        Ok(RateLimiter {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        })
+    }
+
+    fn allow(&mut self, ip: IpAddr) -> bool {
+        let t = now();
+        if self.buckets.len() > 10_000 {
+            // Forget clients whose buckets have refilled anyway
+            let (rate, burst) = (self.rate, self.burst);
+            self.buckets
+                .retain(|_, (tokens, last)| *tokens + (t - *last) * rate < burst);
+        }
+        let (tokens, last) = self.buckets.entry(ip).or_insert((self.burst, t));
+        *tokens = (*tokens + (t - *last) * self.rate).min(self.burst);
+        *last = t;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
+type Limiter = Arc<std::sync::Mutex<RateLimiter>>;
+
+async fn rate_limit(
+    State(limiter): State<Limiter>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let allowed = limiter.lock().unwrap().allow(peer.ip());
+    if !allowed {
+        return TxError::RateLimited.into_response();
+    }
+    next.run(req).await
+}
+
+/// Shared secret for mutating routes; `None` leaves them open.
+type ApiToken = Option<Arc<str>>;
+
+/// Whether `headers` carry `Authorization: Bearer` with the token, if one
+/// is set.
+fn authorized(token: &ApiToken, headers: &HeaderMap) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+    // Compare every byte so timing doesn't reveal the matching prefix
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn require_token(State(token): State<ApiToken>, req: Request, next: Next) -> Response {
+    if !authorized(&token, req.headers()) {
+        return TxError::Unauthorized.into_response();
+    }
+    next.run(req).await
+}
+
+/// Work for the journal task, done in the order it's sent.
+enum Journal {
+    Entry(TxLog),
+    Patch(Box<Patch>),
+    // Empty the file once `saved` says a save covering it is written
+    Truncate {
+        saved: oneshot::Receiver<bool>,
+        done: oneshot::Sender<io::Result<()>>,
+    },
+}
+
+/// Append every transaction to `path` as a JSON line, and after each run of
+/// commands a patch of what they changed. Disk I/O happens on the spawned
+/// task; callers only enqueue.
+fn spawn_journal(
+    path: &FsPath,
+) -> io::Result<(mpsc::UnboundedSender<Journal>, tokio::task::JoinHandle<()>)> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let mut out = BufWriter::new(tokio::fs::File::from_std(file));
+    let (tx, mut rx) = mpsc::unbounded_channel::<Journal>();
+    let task = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            let line = match msg {
+                Journal::Entry(entry) => serde_json::to_vec(&entry),
+                Journal::Patch(patch) => {
+                    exact(|| serde_json::to_vec(&PatchLine { patch: Some(patch) }))
+                }
+                // Appends carry on from the new end, so the file stays
+                // open. If the save failed nothing else covers the file,
+                // so it's kept
+                Journal::Truncate { saved, done } => {
+                    let res = async {
+                        out.flush().await?;
+                        if saved.await == Ok(true) {
+                            out.get_ref().set_len(0).await?;
+                            out.get_ref().sync_all().await?;
+                        }
+                        Ok(())
+                    };
+                    let _ = done.send(res.await);
+                    continue;
+                }
+            };
+            let mut line = line.unwrap_or_default();
+            line.push(b'\n');
+            if out.write_all(&line).await.is_err() {
+                break;
+            }
+            // Flush once the queue drains rather than per entry
+            if rx.is_empty() && out.flush().await.is_err() {
+                break;
+            }
+        }
+        let _ = out.flush().await;
+    });
+    Ok((tx, task))
+}
+
+/// Body of the payout webhook.
+#[derive(Serialize)]
+struct Payout {
+    winner: String,
+    amount: Money,
+    contract: String,
+    t: f64,
+}
+
+/// POST each payout to `url` as JSON, in order, retrying failures with
+/// backoff. Requests go out on the spawned task; callers only enqueue.
+fn spawn_webhook(url: String) -> mpsc::UnboundedSender<Payout> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Payout>();
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+        while let Some(payout) = rx.recv().await {
+            for attempt in 0..WEBHOOK_ATTEMPTS {
+                let res = client.post(&url).json(&payout).send().await;
+                match res.and_then(|r| r.error_for_status()) {
+                    Ok(_) => break,
+                    Err(e) if attempt + 1 == WEBHOOK_ATTEMPTS => {
+                        tracing::warn!(%url, error = %e, "webhook delivery failed");
+                    }
+                    Err(_) => {
+                        tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+                    }
+                }
+            }
+        }
+    });
+    tx
+}
+
+/// CORS policy from `CORS_ORIGINS` and `CORS_METHODS`, both comma-separated.
+/// Without `CORS_ORIGINS` any origin is allowed, which suits local dev.
+fn cors_layer() -> Result<CorsLayer, String> {
+    let methods =
+        env_list::<Method>("CORS_METHODS")?.unwrap_or_else(|| vec![Method::GET, Method::POST]);
+    let cors = CorsLayer::new()
+        .allow_methods(methods)
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]);
+    Ok(match env_list::<HeaderValue>("CORS_ORIGINS")? {
+        Some(origins) => cors.allow_origin(origins),
+        None => cors.allow_origin(Any),
+    })
+}
+
+/// Resolves on SIGINT or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let term = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let term = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = term => {}
+    }
+}
+
+async fn index() -> Html<&'static str> {
+    Html(include_str!("index.html"))
+}
+
+/// Counts open WebSocket connections against `--ws-max-connections`.
+#[derive(Clone)]
+struct WsSlots {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// One connection's place under the cap, given back when dropped.
+struct WsSlot(Arc<AtomicUsize>);
+
+impl WsSlots {
+    fn take(&self) -> Option<WsSlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| WsSlot(self.open.clone()))
+    }
+}
+
+impl Drop for WsSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// What every WebSocket connection shares besides the state.
+#[derive(Clone)]
+struct WsCfg {
+    ping: std::time::Duration,
+    slots: WsSlots,
+    // Sends made over the socket take the same path as /api/send
+    queue: Queue,
+    limiter: Limiter,
+    token: ApiToken,
+}
+
+/// A command from a WebSocket client, in the connection's wire format.
+/// Each is answered with a `result` frame, in the order they arrive.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMsg {
+    Send(SendReq),
+}
+
+/// Carry out one client command. With `API_TOKEN` set, only connections
+/// that upgraded with the bearer token may send.
+async fn ws_command(
+    msg: Option<ClientMsg>,
+    cfg: &WsCfg,
+    peer: IpAddr,
+    authorized: bool,
+) -> SendRes {
+    let res = async {
+        let ClientMsg::Send(req) = msg.ok_or(TxError::BadCommand)?;
+        if !authorized {
+            return Err(TxError::Unauthorized);
+        }
+        if !cfg.limiter.lock().unwrap().allow(peer) {
+            return Err(TxError::RateLimited);
+        }
+        enqueue(&cfg.queue, req).await
+    };
+    res.await.unwrap_or_else(SendRes::err)
+}
+
+async fn ws_upgrade(
+    ws: WebSocketUpgrade,
+    State(s): State<S>,
+    Query(enc): Query<Wire>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    cfg: WsCfg,
+) -> Response {
+    let Some(slot) = cfg.slots.take() else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many WebSocket connections",
+        )
+            .into_response();
+    };
+    // A failed upgrade drops the callback, and the slot with it
+    let authorized = authorized(&cfg.token, &headers);
+    ws.on_upgrade(move |sock| async move {
+        ws_handler(sock, s, enc, cfg, peer.ip(), authorized).await;
+        drop(slot);
+    })
+}
+
+async fn ws_handler(
+    mut sock: WebSocket,
+    state: S,
+    enc: Wire,
+    cfg: WsCfg,
+    peer: IpAddr,
+    authorized: bool,
+) {
+    // Full snapshot first, then only what changed since the last frame
+    let (snap, mut since, mut epoch, mut rx) = {
+        let app = state.read().await;
+        (
+            Frame::Snapshot(Box::new(app.snapshot())),
+            app.next_id,
+            app.epoch,
+            app.notify.subscribe(),
+        )
+    };
+    if sock.send(snap.message(enc)).await.is_err() {
+        return;
+    }
+    // Deltas carry stored wallets, so the first one after a snapshot has
+    // them all to replace the projected values, and clients extrapolate
+    // from each wallet's `t` from then on. Once a client has had that and
+    // is level with the previous update, the shared delta fits as is
+    let mut synced = false;
+
+    // Ping every `ping`, and give up on a peer that hasn't answered the
+    // previous one by the next. Client pings are answered by axum itself
+    let ping = cfg.ping;
+    let mut heartbeat = tokio::time::interval_at(tokio::time::Instant::now() + ping, ping);
+    let mut awaiting_pong = false;
+
+    loop {
+        tokio::select! {
+            _ = heartbeat.tick() => {
+                if awaiting_pong || sock.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+                awaiting_pong = true;
+            }
+            r = rx.recv() => {
+                // A client that fell behind the channel skips what it
+                // missed and resyncs from a full snapshot
+                let update = match r {
+                    Ok(u) => Some(u),
+                    Err(RecvError::Lagged(n)) => {
+                        state.read().await.lag.record(n);
+                        None
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let msg = match update {
+                    Some(u) if synced && u.epoch == epoch && u.since == since => {
+                        since = u.next_id;
+                        u.delta.message(enc)
+                    }
+                    // After a reset the old wallets and ids mean nothing, so
+                    // start the client over from a full snapshot
+                    Some(u) if u.epoch != epoch => {
+                        (epoch, since, synced) = (u.epoch, u.next_id, false);
+                        u.snapshot.message(enc)
+                    }
+                    update => {
+                        let app = state.read().await;
+                        let frame = if update.is_none() || app.epoch != epoch {
+                            epoch = app.epoch;
+                            synced = false;
+                            Frame::Snapshot(Box::new(app.snapshot()))
+                        } else {
+                            synced = true;
+                            Frame::Delta(app.delta(&[], since))
+                        };
+                        since = app.next_id;
+                        frame.message(enc)
+                    }
+                };
+                if sock.send(msg).await.is_err() { break; }
+            }
+            msg = sock.recv() => {
+                match msg {
+                    Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(Message::Text(text))) => {
+                        let cmd = serde_json::from_str(&text).ok();
+                        let res = ws_command(cmd, &cfg, peer, authorized).await;
+                        if sock.send(Frame::Result(res).message(enc)).await.is_err() { break; }
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        let cmd = rmp_serde::from_slice(&bytes).ok();
+                        let res = ws_command(cmd, &cfg, peer, authorized).await;
+                        if sock.send(Frame::Result(res).message(enc)).await.is_err() { break; }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Full snapshot on connect and after every notification, for clients that
+/// can't speak WebSocket. Frames match the WebSocket `snapshot` frames.
+async fn events_handler(State(s): State<S>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let rx = s.read().await.notify.subscribe();
+    let events = stream::unfold((s, rx, true), |(s, mut rx, first)| async move {
+        let shared = if first {
+            None
+        } else {
+            match rx.recv().await {
+                Ok(u) => Some(u.snapshot.message(Wire::PLAIN)),
+                Err(RecvError::Lagged(n)) => {
+                    s.read().await.lag.record(n);
+                    None
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        };
+        let data = match shared {
+            Some(Message::Text(data)) => data,
+            _ => Frame::Snapshot(Box::new(s.read().await.snapshot())).to_json(),
+        };
+        Some((Ok(Event::default().data(data)), (s, rx, false)))
+    });
+    Sse::new(events).keep_alive(KeepAlive::new().interval(std::time::Duration::from_secs(15)))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SendReq {
+    from: String,
+    to: String,
+    amount: Money,
+    // Retries with the same key get the first response instead of a resend
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    // The sender's last nonce plus one, signed with `signing_payload`
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    signature: Option<String>, // hex, 64 bytes
+    // Report what the send would do without applying it; not in batches
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Clone, Default, Serialize, ToSchema)]
+struct SendRes {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<TxError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    /// `transfer`, or for a self-send `settle` (vested funds claimed) or
+    /// `earlysettle` (locked funds withdrawn too, for a fee)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<TxKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from_balance: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_balance: Option<Money>,
+    /// What the recipient was credited, net of any fee the sender's other
+    /// funds couldn't cover
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivered: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fee: Option<Money>,
+    /// Vested funds a self-send moved to the balance
+    #[serde(skip_serializing_if = "Option::is_none")]
+    claimed: Option<Money>,
+}
+
+impl SendRes {
+    fn ok() -> Self {
+        SendRes {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn err(e: TxError) -> Self {
+        SendRes {
+            error: Some(e.to_string()),
+            code: Some(e),
+            ..Default::default()
+        }
+    }
+}
+
+/// Send `amount` from one wallet to another. Naming the same wallet twice
+/// settles it instead: vested funds are claimed, and a positive `amount`
+/// also withdraws that much of its locked funds early, for a fee. `kind`
+/// in the response says which happened.
+#[utoipa::path(post, path = "/api/send", tag = "transfers", request_body = SendReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 403, description = "Missing or invalid signature", body = SendRes), (status = 409, description = "Stale nonce", body = SendRes), (status = 423, description = "Sender or recipient frozen", body = SendRes), (status = 429, description = "Rate limited", body = SendRes), (status = 400, description = "Invalid transfer", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 422, description = "Insufficient funds", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn send_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<SendReq>,
+) -> Result<Json<SendRes>, TxError> {
+    enqueue(&queue, req).await.map(Json)
+}
+
+/// Hand `req` to the queue's worker and wait for its answer.
+async fn enqueue(queue: &Queue, req: SendReq) -> Result<SendRes, TxError> {
+    apply(queue, move |app| app.submit(req)).await?
+}
+
+/// Run `f` on the queue's worker, after everything queued before it, and
+/// wait for what it returns. A full queue turns the request away.
+async fn apply<T, F>(queue: &Queue, f: F) -> Result<T, TxError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut App) -> T + Send + 'static,
+{
+    let (reply, res) = oneshot::channel();
+    // The caller may have gone; the change stands regardless
+    let cmd: Command = Box::new(move |app| {
+        let _ = reply.send(f(app));
+    });
+    queue.try_send(cmd).map_err(|_| TxError::QueueFull)?;
+    res.await.map_err(|_| TxError::QueueFull)
+}
+
+/// A change to the state, made by the queue's worker.
+type Command = Box<dyn FnOnce(&mut App) + Send>;
+
+type Queue = mpsc::Sender<Command>;
+
+/// The queue's only consumer, and so the only thing that changes the
+/// state. Commands are applied strictly in arrival order, and whatever has
+/// piled up shares one write lock, so a burst of requests contends with
+/// readers once rather than once per request.
+async fn run_queue(s: S, mut rx: mpsc::Receiver<Command>) {
+    let mut batch = Vec::with_capacity(QUEUE_BATCH);
+    while rx.recv_many(&mut batch, QUEUE_BATCH).await > 0 {
+        let mut app = s.write().await;
+        for cmd in batch.drain(..) {
+            cmd(&mut app);
+        }
+        app.journal_patch();
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BatchReq {
+    transfers: Vec<SendReq>,
+}
+
+#[derive(Default, Serialize, ToSchema)]
+struct BatchRes {
+    ok: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ids: Vec<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<TxError>,
+}
+
+#[utoipa::path(post, path = "/api/send/batch", tag = "transfers", request_body = BatchReq, security(("bearer" = [])), responses((status = 200, body = BatchRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 400, description = "A transfer failed; none were applied", body = BatchRes), (status = 429, description = "Rate limited", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn batch_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<BatchReq>,
+) -> (StatusCode, Json<BatchRes>) {
+    let fail = |index: Option<usize>, e: TxError| {
+        let res = BatchRes {
+            index,
+            error: Some(e.to_string()),
+            code: Some(e),
+            ..Default::default()
+        };
+        (e.status(), Json(res))
+    };
+    match apply(&queue, move |app| app.submit_batch(&req.transfers)).await {
+        Ok(Ok(ids)) => {
+            let res = BatchRes {
+                ok: true,
+                ids,
+                ..Default::default()
+            };
+            (StatusCode::OK, Json(res))
+        }
+        Ok(Err((k, e))) => fail(Some(k), e),
+        Err(e) => fail(None, e),
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ProjectReq {
+    name: String,
+    seconds_ahead: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ProjectRes {
+    locked: Money,
+    vested: Money,
+    balance: Money,
+    t: f64,
+}
+
+#[utoipa::path(post, path = "/api/project", tag = "wallets", request_body = ProjectReq, responses((status = 200, body = ProjectRes), (status = 400, body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes)))]
+async fn project_handler(
+    State(s): State<S>,
+    Json(req): Json<ProjectReq>,
+) -> Result<Json<ProjectRes>, TxError> {
+    let app = s.read().await;
+    let i = app.index_of(&req.name).ok_or(TxError::UnknownWallet)?;
+    let w = app.project(i, req.seconds_ahead)?;
+    Ok(Json(ProjectRes {
+        locked: w.locked,
+        vested: w.vested,
+        balance: w.balance,
+        t: w.t,
+    }))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct InterestReq {
+    since: f64, // Unix seconds
+}
+
+#[derive(Serialize, ToSchema)]
+struct InterestRes {
+    interest: Money,
+    since: f64,
+    t: f64,
+}
+
+/// What the wallet would have earned in interest between `since` and now
+/// on its current balance and vested funds, for reporting. Nothing is
+/// settled.
+#[utoipa::path(post, path = "/api/wallet/{name}/interest", tag = "wallets", params(("name" = String, Path, description = "Wallet name")), request_body = InterestReq, responses((status = 200, body = InterestRes), (status = 400, description = "since is in the future, or the wallet earns no interest", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes)))]
+async fn interest_handler(
+    State(s): State<S>,
+    Path(name): Path<String>,
+    Json(req): Json<InterestReq>,
+) -> Result<Json<InterestRes>, TxError> {
+    let app = s.read().await;
+    let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+    Ok(Json(InterestRes {
+        interest: app.interest_since(i, req.since)?,
+        since: req.since,
+        t: app.clock.now(),
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct ScheduleQuery {
+    /// Points to project, 12 by default
+    steps: Option<usize>,
+    /// Days between points, 30 by default
+    interval_days: Option<f64>,
+}
+
+/// Where a wallet's locked funds will stand at each of the next `steps`
+/// intervals if it neither sends nor receives. Points are cumulative, so
+/// `vested` is what a claim at that time would move to the balance.
+#[utoipa::path(get, path = "/api/wallet/{name}/schedule", tag = "wallets", params(("name" = String, Path, description = "Wallet name"), ScheduleQuery), responses((status = 200, body = Vec<ProjectRes>), (status = 400, body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes)))]
+async fn schedule_handler(
+    State(s): State<S>,
+    Path(name): Path<String>,
+    Query(q): Query<ScheduleQuery>,
+) -> Result<Json<Vec<ProjectRes>>, TxError> {
+    let app = s.read().await;
+    let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+    if app.wallets[i].locked <= Money::ZERO {
+        return Ok(Json(Vec::new()));
+    }
+    let steps = q.steps.unwrap_or(12).min(MAX_SCHEDULE_STEPS);
+    let interval = q.interval_days.unwrap_or(30.0) * 86400.0;
+    let points = (1..=steps)
+        .map(|k| {
+            let w = app.project(i, k as f64 * interval)?;
+            Ok(ProjectRes {
+                locked: w.locked,
+                vested: w.vested,
+                balance: w.balance,
+                t: w.t,
+            })
+        })
+        .collect::<Result<_, TxError>>()?;
+    Ok(Json(points))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct LogQuery {
+    from: Option<String>,
+    to: Option<String>,
+    min_amount: Option<Money>,
+    since: Option<f64>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Matching log entries, newest first. `limit` defaults to 100.
+#[utoipa::path(get, path = "/api/log", tag = "log", params(LogQuery), responses((status = 200, body = Vec<TxLog>)))]
+async fn log_handler(State(s): State<S>, Query(q): Query<LogQuery>) -> Json<Vec<TxLog>> {
+    let app = s.read().await;
+    let entries = app
+        .log
+        .iter()
+        .rev()
+        .filter(|e| q.from.as_ref().is_none_or(|f| e.from == *f))
+        .filter(|e| q.to.as_ref().is_none_or(|t| e.to == *t))
+        .filter(|e| q.min_amount.is_none_or(|m| e.amount >= m))
+        .filter(|e| q.since.is_none_or(|t| e.t >= t))
+        .skip(q.offset.unwrap_or(0))
+        .take(q.limit.unwrap_or(100))
+        .cloned()
+        .collect();
+    Json(entries)
+}
+
+#[utoipa::path(get, path = "/api/tx/{id}", tag = "log", params(("id" = u64, Path, description = "Transaction id")), responses((status = 200, body = TxLog), (status = 404, description = "No such transaction", body = SendRes), (status = 410, description = "Dropped from the in-memory log", body = SendRes)))]
+async fn tx_handler(State(s): State<S>, Path(id): Path<u64>) -> Result<Json<TxLog>, TxError> {
+    let app = s.read().await;
+    if let Some(e) = app.log.iter().rev().find(|e| e.id == id) {
+        return Ok(Json(e.clone()));
+    }
+    // Ids ascend through the log, so one below its oldest entry aged out
+    match app.log.front() {
+        Some(oldest) if id < oldest.id => Err(TxError::Evicted),
+        _ => Err(TxError::UnknownTx),
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+struct HistoryQuery {
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Log entries a wallet sent or received, newest first, including its own
+/// settlements and any payouts it won. `limit` defaults to 100.
+#[utoipa::path(get, path = "/api/wallet/{name}/history", tag = "wallets", params(("name" = String, Path, description = "Wallet name"), HistoryQuery), responses((status = 200, body = Vec<TxLog>), (status = 404, description = "Unknown wallet", body = SendRes)))]
+async fn history_handler(
+    State(s): State<S>,
+    Path(name): Path<String>,
+    Query(q): Query<HistoryQuery>,
+) -> Result<Json<Vec<TxLog>>, TxError> {
+    let app = s.read().await;
+    app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+    let entries = app
+        .log
+        .iter()
+        .rev()
+        .filter(|e| e.from == name || e.to == name)
+        .skip(q.offset.unwrap_or(0))
+        .take(q.limit.unwrap_or(100))
+        .cloned()
+        .collect();
+    Ok(Json(entries))
+}
+
+/// Quote a CSV field if it holds a delimiter, quote, or newline.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// The in-memory log as a CSV download, oldest first.
+#[utoipa::path(get, path = "/api/log.csv", tag = "log", responses((status = 200, description = "`id,from,to,amount,fee,kind,t` rows", content_type = "text/csv", body = String)))]
+async fn log_csv_handler(State(s): State<S>) -> impl IntoResponse {
+    let app = s.read().await;
+    let mut out = String::from("id,from,to,amount,fee,kind,t\n");
+    for e in &app.log {
+        let kind = serde_json::to_value(e.kind).unwrap_or_default();
+        out += &format!(
+            "{},{},{},{},{},{},{}\n",
+            e.id,
+            csv_field(&e.from),
+            csv_field(&e.to),
+            e.amount.to_f64(),
+            e.fee.to_f64(),
+            kind.as_str().unwrap_or_default(),
+            e.t,
+        );
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"log.csv\"",
+            ),
+        ],
+        out,
+    )
+}
+
+#[utoipa::path(get, path = "/api/contributions", tag = "economy", responses((status = 200, body = Vec<ContractOdds>)))]
+async fn contributions_handler(State(s): State<S>) -> Json<Vec<ContractOdds>> {
+    Json(s.read().await.odds())
+}
+
+#[derive(Deserialize, IntoParams)]
+struct LeaderboardQuery {
+    /// How many to return, 10 by default
+    n: Option<usize>,
+}
+
+#[utoipa::path(get, path = "/api/leaderboard", tag = "economy", params(LeaderboardQuery), responses((status = 200, body = Vec<Rank>)))]
+async fn leaderboard_handler(
+    State(s): State<S>,
+    Query(q): Query<LeaderboardQuery>,
+) -> Json<Vec<Rank>> {
+    Json(s.read().await.leaderboard(q.n.unwrap_or(10)))
+}
+
+#[utoipa::path(get, path = "/api/supply", tag = "economy", responses((status = 200, body = Supply)))]
+async fn supply_handler(State(s): State<S>) -> Json<Supply> {
+    Json(s.read().await.supply())
+}
+
+#[utoipa::path(get, path = "/api/invariant", tag = "economy", responses((status = 200, body = Invariant)))]
+async fn invariant_handler(State(s): State<S>) -> Json<Invariant> {
+    Json(s.read().await.invariant())
+}
+
+#[utoipa::path(get, path = "/api/stats", tag = "economy", responses((status = 200, body = Stats)))]
+async fn stats_handler(State(s): State<S>) -> Json<Stats> {
+    Json(s.read().await.stats())
+}
+
+#[utoipa::path(get, path = "/metrics", tag = "economy", responses((status = 200, description = "Prometheus text format", content_type = "text/plain", body = String)))]
+async fn metrics_handler(State(s): State<S>) -> impl IntoResponse {
+    let body = s.read().await.metrics();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
+
+/// Liveness: answers without touching the state, so a wedged lock
+/// doesn't get the process restarted on top of whatever wedged it.
+#[utoipa::path(get, path = "/healthz", tag = "probes", responses((status = 200, description = "Process is up", body = String)))]
+async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+/// Readiness: 200 once the state is loaded and the simulation is running,
+/// 503 before that and again while shutting down.
+#[utoipa::path(get, path = "/readyz", tag = "probes", responses((status = 200, description = "Serving", body = String), (status = 503, description = "Starting up or shutting down", body = String)))]
+async fn readyz_handler(ready: Arc<AtomicBool>) -> (StatusCode, &'static str) {
+    if ready.load(Ordering::Acquire) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReferralReq {
+    enabled: Option<bool>,
+    bonus: Option<Money>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReferralRes {
+    enabled: bool,
+    bonus: Money,
+}
+
+/// Turn the referral bonus on or off and set its size; fields left out
+/// keep their current value.
+#[utoipa::path(post, path = "/api/referral", tag = "simulation", request_body = ReferralReq, security(("bearer" = [])), responses((status = 200, body = ReferralRes), (status = 400, description = "Negative bonus", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn referral_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<ReferralReq>,
+) -> Result<Json<ReferralRes>, TxError> {
+    if req.bonus.is_some_and(|b| b < Money::ZERO) {
+        return Err(TxError::NegativeAmount);
+    }
+    apply(&queue, move |app| {
+        app.cfg.referral = req.enabled.unwrap_or(app.cfg.referral);
+        app.cfg.referral_bonus = req.bonus.unwrap_or(app.cfg.referral_bonus);
+        app.touch();
+        Json(ReferralRes {
+            enabled: app.cfg.referral,
+            bonus: app.cfg.referral_bonus,
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, ToSchema)]
+struct MintReq {
+    amount: Money,
+}
+
+/// Add `amount` to the supply, paid into Koi's balance.
+#[utoipa::path(post, path = "/api/admin/mint", tag = "simulation", request_body = MintReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 400, description = "Amount not positive, or past the maximum supply", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn mint_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<MintReq>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let id = app.mint(req.amount)?;
+        Ok(Json(SendRes {
+            id: Some(id),
+            to_balance: Some(app.wallets[0].balance),
+            ..SendRes::ok()
+        }))
+    })
+    .await?
+}
+
+#[utoipa::path(post, path = "/api/admin/undo", tag = "simulation", security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 409, description = "Nothing undoable", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn undo_handler(State(queue): State<Queue>) -> Result<Json<SendRes>, TxError> {
+    let id = apply(&queue, App::undo).await??;
+    Ok(Json(SendRes {
+        id: Some(id),
+        ..SendRes::ok()
+    }))
+}
+
+#[utoipa::path(post, path = "/api/sim/pause", tag = "simulation", security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn pause_handler(State(queue): State<Queue>) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, |app| app.set_paused(true)).await?;
+    Ok(Json(SendRes::ok()))
+}
+
+#[utoipa::path(post, path = "/api/sim/resume", tag = "simulation", security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn resume_handler(State(queue): State<Queue>) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, |app| app.set_paused(false)).await?;
+    Ok(Json(SendRes::ok()))
+}
+
+#[utoipa::path(post, path = "/api/sim/reset", tag = "simulation", security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn reset_handler(State(queue): State<Queue>) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, App::reset).await?;
+    Ok(Json(SendRes::ok()))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BurnReq {
+    name: String,
+    amount: Money,
+    // The wallet's last nonce plus one, signed with `burn_payload`
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+#[utoipa::path(post, path = "/api/burn", tag = "transfers", request_body = BurnReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 403, description = "Missing or invalid signature", body = SendRes), (status = 409, description = "Stale nonce", body = SendRes), (status = 400, description = "Invalid transfer", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 422, description = "Insufficient funds", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn burn_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<BurnReq>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&req.name).ok_or(TxError::UnknownWallet)?;
+        let payload = burn_payload(&req.name, req.amount, req.nonce);
+        let last = app.wallets[i].nonce;
+        app.verify_signed(i, &payload, req.signature.as_deref(), req.nonce, last)?;
+        let id = app.burn(i, req.amount)?;
+        app.wallets[i].nonce = req.nonce;
+        Ok(Json(SendRes {
+            id: Some(id),
+            from_balance: Some(app.wallets[i].balance),
+            ..SendRes::ok()
+        }))
+    })
+    .await?
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AirdropReq {
+    total: Money,
+    recipients: Vec<AirdropWeight>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct AirdropWeight {
+    name: String,
+    weight: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Airdropped {
+    name: String,
+    amount: Money,
+    id: Option<u64>,
+}
+
+/// Gift `total` from Koi across the recipients in proportion to their
+/// weights. Either every share is sent or none are.
+#[utoipa::path(post, path = "/api/airdrop", tag = "transfers", request_body = AirdropReq, security(("bearer" = [])), responses((status = 200, body = Vec<Airdropped>), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 400, description = "Invalid airdrop", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 422, description = "Koi can't cover the total", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn airdrop_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<AirdropReq>,
+) -> Result<Json<Vec<Airdropped>>, TxError> {
+    apply(&queue, move |app| {
+        let weights = req
+            .recipients
+            .iter()
+            .map(|r| Some((app.index_of(&r.name)?, r.weight)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(TxError::UnknownWallet)?;
+        let sent = app.airdrop(req.total, &weights)?;
+        app.check_contracts();
+        Ok(Json(
+            sent.into_iter()
+                .map(|(i, amount, id)| Airdropped {
+                    name: app.wallets[i].name.clone(),
+                    amount,
+                    id,
+                })
+                .collect(),
+        ))
+    })
+    .await?
+}
+
+#[derive(Deserialize, ToSchema)]
+struct StakeReq {
+    name: String,
+    amount: Money,
+    // Signed as a send of `amount` to Staking
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+/// Stake by sending to the staking contract; the usual fee applies.
+#[utoipa::path(post, path = "/api/stake", tag = "transfers", request_body = StakeReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 403, description = "Missing or invalid signature", body = SendRes), (status = 409, description = "Stale nonce", body = SendRes), (status = 400, description = "Invalid transfer", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 422, description = "Insufficient funds", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn stake_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<StakeReq>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&req.name).ok_or(TxError::UnknownWallet)?;
+        let staking = &app.wallets[STAKING_IDX].name;
+        let payload = signing_payload(&req.name, staking, req.amount, req.nonce);
+        let last = app.wallets[i].nonce;
+        app.verify_signed(i, &payload, req.signature.as_deref(), req.nonce, last)?;
+        let id = app.send(i, STAKING_IDX, req.amount)?;
+        app.wallets[i].nonce = req.nonce;
+        Ok(Json(SendRes {
+            id,
+            from_balance: Some(app.wallets[i].balance),
+            ..SendRes::ok()
+        }))
+    })
+    .await?
+}
+
+#[derive(Deserialize, ToSchema)]
+struct UnstakeReq {
+    name: String,
+    // The wallet's last nonce plus one, signed with `unstake_payload`
+    #[serde(default)]
+    nonce: u64,
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+#[utoipa::path(post, path = "/api/unstake", tag = "transfers", request_body = UnstakeReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 403, description = "Missing or invalid signature", body = SendRes), (status = 409, description = "Stale nonce", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 422, description = "Nothing staked", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn unstake_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<UnstakeReq>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&req.name).ok_or(TxError::UnknownWallet)?;
+        let payload = unstake_payload(&req.name, req.nonce);
+        let last = app.wallets[i].nonce;
+        app.verify_signed(i, &payload, req.signature.as_deref(), req.nonce, last)?;
+        let id = app.unstake(i)?;
+        app.wallets[i].nonce = req.nonce;
+        Ok(Json(SendRes {
+            id: Some(id),
+            to_balance: Some(app.wallets[i].balance),
+            ..SendRes::ok()
+        }))
+    })
+    .await?
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateWalletReq {
+    name: String,
+    #[serde(default)]
+    contract: bool,
+    // Hex ed25519 public key whose secret half, kept by the client, signs
+    // for the wallet; contracts have none
+    #[serde(default)]
+    pubkey: Option<String>,
+}
+
+#[utoipa::path(post, path = "/api/wallet", tag = "wallets", request_body = CreateWalletReq, security(("bearer" = [])), responses((status = 201, body = WalletRes), (status = 400, description = "Invalid name or key", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 409, description = "Name taken", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn create_wallet_handler(
+    State(queue): State<Queue>,
+    Json(req): Json<CreateWalletReq>,
+) -> Result<(StatusCode, Json<WalletRes>), TxError> {
+    let res = apply(&queue, move |app| {
+        let i = app.create_wallet(req.name, req.contract, req.pubkey.as_deref())?;
+        Ok(app.wallet_res(i))
+    })
+    .await??;
+    Ok((StatusCode::CREATED, Json(res)))
+}
+
+#[utoipa::path(post, path = "/api/wallet/{name}/freeze", tag = "wallets", params(("name" = String, Path, description = "Wallet name")), security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 400, description = "Koi or a contract", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn freeze_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        app.set_frozen(i, true)
+    })
+    .await??;
+    Ok(Json(SendRes::ok()))
+}
+
+#[utoipa::path(post, path = "/api/wallet/{name}/unfreeze", tag = "wallets", params(("name" = String, Path, description = "Wallet name")), security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 400, description = "Koi or a contract", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn unfreeze_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        app.set_frozen(i, false)
+    })
+    .await??;
+    Ok(Json(SendRes::ok()))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ContractConfigReq {
+    threshold: Option<Money>,
+    share: Option<f64>,
+}
+
+/// Retune a lottery contract; fields left out keep their current value.
+#[utoipa::path(post, path = "/api/contract/{name}/config", tag = "wallets", params(("name" = String, Path, description = "Contract name")), request_body = ContractConfigReq, security(("bearer" = [])), responses((status = 200, body = ContractInfo), (status = 400, description = "Threshold or share out of range", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 404, description = "Unknown wallet or not a lottery contract", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn contract_config_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+    Json(req): Json<ContractConfigReq>,
+) -> Result<Json<ContractInfo>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        let info = app.configure_contract(i, req.threshold, req.share)?;
+        app.check_contracts();
+        Ok(Json(info))
+    })
+    .await?
+}
+
+#[derive(Deserialize, ToSchema)]
+struct RateReq {
+    multiplier: f64,
+}
+
+#[utoipa::path(post, path = "/api/wallet/{name}/rate", tag = "wallets", params(("name" = String, Path, description = "Wallet name")), request_body = RateReq, security(("bearer" = [])), responses((status = 200, body = SendRes), (status = 400, description = "Koi, a contract, or a multiplier out of range", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn rate_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+    Json(req): Json<RateReq>,
+) -> Result<Json<SendRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        app.set_rate_multiplier(i, req.multiplier)
+    })
+    .await??;
+    Ok(Json(SendRes::ok()))
+}
+
+#[derive(Serialize, ToSchema)]
+struct WalletRes {
+    #[serde(flatten)]
+    wallet: Wallet,
+    next_nonce: u64, // what the next signed send must carry
+    apy: f64,        // this wallet's yield at Koi's current balance, multiplier included
+}
+
+#[utoipa::path(get, path = "/api/wallet/{name}", tag = "wallets", params(("name" = String, Path, description = "Wallet name")), responses((status = 200, body = WalletRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn wallet_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+) -> Result<Json<WalletRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        app.settle(i);
+        Ok(Json(app.wallet_res(i)))
+    })
+    .await?
+}
+
+#[derive(Deserialize, IntoParams)]
+struct SettleQuery {
+    /// Also move vested funds to the balance
+    #[serde(default)]
+    claim: bool,
+}
+
+#[utoipa::path(post, path = "/api/settle/{name}", tag = "wallets", params(("name" = String, Path, description = "Wallet name"), SettleQuery), security(("bearer" = [])), responses((status = 200, body = WalletRes), (status = 400, description = "Koi or a contract", body = SendRes), (status = 401, description = "Missing or invalid API token", body = SendRes), (status = 404, description = "Unknown wallet", body = SendRes), (status = 503, description = "Transaction queue full", body = SendRes)))]
+async fn settle_handler(
+    State(queue): State<Queue>,
+    Path(name): Path<String>,
+    Query(q): Query<SettleQuery>,
+) -> Result<Json<WalletRes>, TxError> {
+    apply(&queue, move |app| {
+        let i = app.index_of(&name).ok_or(TxError::UnknownWallet)?;
+        app.settle_now(i, q.claim)?;
+        Ok(Json(app.wallet_res(i)))
+    })
+    .await?
+}
+
+/// Mutating routes take a bearer token when the server sets `API_TOKEN`.
+struct BearerAuth;
+
+impl utoipa::Modify for BearerAuth {
+    fn modify(&self, api: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+        let scheme = HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build();
+        api.components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("bearer", SecurityScheme::Http(scheme));
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Koi economy simulator"),
+    paths(
+        send_handler,
+        batch_handler,
+        burn_handler,
+        airdrop_handler,
+        stake_handler,
+        unstake_handler,
+        wallet_handler,
+        create_wallet_handler,
+        freeze_handler,
+        unfreeze_handler,
+        rate_handler,
+        contract_config_handler,
+        settle_handler,
+        project_handler,
+        schedule_handler,
+        interest_handler,
+        history_handler,
+        tx_handler,
+        log_handler,
+        log_csv_handler,
+        contributions_handler,
+        leaderboard_handler,
+        supply_handler,
+        invariant_handler,
+        stats_handler,
+        metrics_handler,
+        healthz_handler,
+        readyz_handler,
+        referral_handler,
+        mint_handler,
+        undo_handler,
+        pause_handler,
+        resume_handler,
+        reset_handler,
+    ),
+    components(schemas(Snapshot)),
+    modifiers(&BearerAuth)
+)]
+struct ApiDoc;
+
+async fn openapi_handler() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[derive(Parser)]
+#[command(about = "Koi economy simulator")]
+struct Args {
+    /// Address to bind
+    #[arg(long, default_value = "0.0.0.0")]
+    addr: String,
+    /// Port to listen on
+    #[arg(long, default_value_t = 3000)]
+    port: u16,
+    /// Number of wallets to create, including the named ones
+    #[arg(long, default_value_t = 100)]
+    wallets: usize,
+    /// Seconds between WebSocket pings; a client that misses one is dropped
+    #[arg(long, default_value_t = 30.0)]
+    ws_ping_interval: f64,
+    /// Most WebSocket clients connected at once; more are refused with 503
+    #[arg(long, default_value_t = 1000)]
+    ws_max_connections: usize,
+    /// Updates queued per subscriber before a slow one falls behind and
+    /// resyncs from a full snapshot
+    #[arg(long, default_value_t = 64)]
+    broadcast_capacity: usize,
+}
+
+/// Every route the server answers. Mutating routes check the token in
+/// `ws_cfg`, and sends its rate limiter too.
+fn router(state: S, ws_cfg: WsCfg, ready: Arc<AtomicBool>, cors: CorsLayer) -> Router {
+    let limiter = ws_cfg.limiter.clone();
+    let mutating = Router::new()
+        .route(
+            "/api/send",
+            post(send_handler).layer(middleware::from_fn_with_state(limiter.clone(), rate_limit)),
+        )
+        .route(
+            "/api/send/batch",
+            post(batch_handler).layer(middleware::from_fn_with_state(limiter, rate_limit)),
+        )
+        .route("/api/burn", post(burn_handler))
+        .route("/api/airdrop", post(airdrop_handler))
+        .route("/api/stake", post(stake_handler))
+        .route("/api/unstake", post(unstake_handler))
+        .route("/api/referral", post(referral_handler))
+        .route("/api/admin/mint", post(mint_handler))
+        .route("/api/admin/undo", post(undo_handler))
+        .route("/api/sim/pause", post(pause_handler))
+        .route("/api/sim/resume", post(resume_handler))
+        .route("/api/sim/reset", post(reset_handler))
+        .route("/api/wallet", post(create_wallet_handler))
+        .route("/api/wallet/:name/freeze", post(freeze_handler))
+        .route("/api/wallet/:name/unfreeze", post(unfreeze_handler))
+        .route("/api/wallet/:name/rate", post(rate_handler))
+        .route("/api/contract/:name/config", post(contract_config_handler))
+        .route("/api/settle/:name", post(settle_handler))
+        .route_layer(middleware::from_fn_with_state(
+            ws_cfg.token.clone(),
+            require_token,
+        ));
+
+    // Compressed when the client accepts it; the predicate leaves tiny
+    // bodies and the /api/events stream alone
+    let api = Router::new()
+        .route("/api/events", get(events_handler))
+        .merge(mutating)
+        .route("/api/wallet/:name", get(wallet_handler))
+        .route("/api/project", post(project_handler))
+        .route("/api/wallet/:name/schedule", get(schedule_handler))
+        .route("/api/wallet/:name/interest", post(interest_handler))
+        .route("/api/wallet/:name/history", get(history_handler))
+        .route("/api/log", get(log_handler))
+        .route("/api/tx/:id", get(tx_handler))
+        .route("/api/log.csv", get(log_csv_handler))
+        .route("/api/contributions", get(contributions_handler))
+        .route("/api/leaderboard", get(leaderboard_handler))
+        .route("/api/supply", get(supply_handler))
+        .route("/api/invariant", get(invariant_handler))
+        .route("/api/stats", get(stats_handler))
+        .route("/api/openapi.json", get(openapi_handler))
+        .layer(CompressionLayer::new());
+
+    let queue = ws_cfg.queue.clone();
+    Router::new()
+        .route("/", get(index))
+        .route(
+            "/ws",
+            get(move |ws, s, q, peer, headers| ws_upgrade(ws, s, q, peer, headers, ws_cfg.clone())),
+        )
+        .merge(api)
+        .route("/metrics", get(metrics_handler))
+        // Swagger UI over /api/openapi.json, its assets built into the binary
+        .merge(SwaggerUi::new("/docs").config(SwaggerConfig::from("/api/openapi.json")))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(move || readyz_handler(ready.clone())))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+                .on_response(DefaultOnResponse::new().level(Level::INFO)),
+        )
+        .layer(cors)
+        .with_state(Ctx { state, queue })
+}
+
+#[tokio::main]
+async fn main() {
+    // RUST_LOG picks the level, e.g. `RUST_LOG=debug` or `RUST_LOG=alice=warn`
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_ansi(io::IsTerminal::is_terminal(&io::stdout()))
+        .init();
+    let args = Args::parse();
+    let cfg = Config::from_env().unwrap_or_else(|e| {
+        eprintln!("config: {e}");
+        std::process::exit(1);
+    });
+    let limiter = RateLimiter::from_env().unwrap_or_else(|e| {
+        eprintln!("config: {e}");
+        std::process::exit(1);
+    });
+    let limiter: Limiter = Arc::new(std::sync::Mutex::new(limiter));
+    let sim_cfg = SimCfg::from_env().unwrap_or_else(|e| {
+        eprintln!("config: {e}");
+        std::process::exit(1);
+    });
+    let scenario = std::env::var_os("SCENARIO_PATH").map(|path| {
+        load_scenario(FsPath::new(&path)).unwrap_or_else(|e| {
+            eprintln!("scenario: {e}");
+            std::process::exit(1);
+        })
+    });
+    // When API_TOKEN is set, mutating routes need `Authorization: Bearer`
+    let token: ApiToken = std::env::var("API_TOKEN")
+        .ok()
+        .filter(|t| !t.is_empty())
+        .map(Into::into);
+    let cors = cors_layer().unwrap_or_else(|e| {
+        eprintln!("config: {e}");
+        std::process::exit(1);
+    });
+    if !(args.ws_ping_interval > 0.0 && args.ws_ping_interval.is_finite()) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--ws-ping-interval must be a positive number of seconds",
+            )
+            .exit();
+    }
+    let ping = std::time::Duration::from_secs_f64(args.ws_ping_interval);
+    if args.broadcast_capacity == 0 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                "--broadcast-capacity must be at least 1",
+            )
+            .exit();
+    }
+    let slots = WsSlots {
+        open: Arc::default(),
+        max: args.ws_max_connections,
+    };
+    let min_wallets = NAMED.len() + cfg.contracts.len();
+    if args.wallets < min_wallets {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("--wallets must be at least {min_wallets}"),
+            )
+            .exit();
+    }
+    if let Some(gifts) = &cfg.gifts {
+        // Koi pays the gifts and contracts can't be given one
+        let giftable: HashSet<String> = wallet_names(&cfg, args.wallets)
+            .skip(1)
+            .filter(|(_, contract)| !contract)
+            .map(|(name, _)| name)
+            .collect();
+        if let Some(name) = gifts.keys().find(|name| !giftable.contains(*name)) {
+            eprintln!("config: GIFTS_PATH names {name:?}, which isn't a wallet that can be gifted");
+            std::process::exit(1);
+        }
+    }
+    // Fixed seed makes gifts and the simulation reproducible across runs
+    let seed = std::env::var("CRYPTO_ALICE_SEED")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok());
+    let journal_path = std::env::var_os("JOURNAL_PATH").map(PathBuf::from);
+    let (journal, journal_task) = journal_path
+        .as_ref()
+        .and_then(|path| {
+            spawn_journal(path)
+                .map_err(|e| tracing::warn!(path = %path.display(), error = %e, "journal disabled"))
+                .ok()
+        })
+        .unzip();
+    let state_path = std::env::var_os("STATE_PATH").map(PathBuf::from);
+    let compact = env_parse("JOURNAL_COMPACT")
+        .unwrap_or_else(|e| {
+            eprintln!("config: {e}");
+            std::process::exit(1);
+        })
+        .unwrap_or(false);
+    let (tx, _) = broadcast::channel(args.broadcast_capacity);
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut app = match &state_path {
+        Some(path) => {
+            let loaded = App::load_from_path(
+                path,
+                journal_path.as_deref(),
+                cfg.clone(),
+                tx.clone(),
+                journal.clone(),
+                seed,
+                clock.clone(),
+            );
+            match loaded {
+                Ok(app) => {
+                    // The App must own the only senders so shutdown can close them
+                    drop((tx, journal));
+                    tracing::info!(path = %path.display(), "loaded state");
+                    app
+                }
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "starting fresh, could not load state");
+                    App::new(cfg, args.wallets, tx, journal, seed, clock)
+                }
+            }
+        }
+        None => App::new(cfg, args.wallets, tx, journal, seed, clock),
+    };
+    // Keys for new wallets, and old ones that lost theirs, go to KEYS_PATH;
+    // without it nobody can sign for them and only the simulation moves them
+    let issued = app.issue_keys();
+    if !issued.is_empty() {
+        match std::env::var_os("KEYS_PATH").map(PathBuf::from) {
+            Some(path) => match save_keys(&path, &issued) {
+                Ok(()) => {
+                    tracing::info!(path = %path.display(), keys = issued.len(), "issued wallet keys")
+                }
+                Err(e) => {
+                    tracing::error!(path = %path.display(), error = %e, "could not save issued keys")
+                }
+            },
+            None => tracing::warn!(
+                keys = issued.len(),
+                "KEYS_PATH unset, issued secret keys discarded"
+            ),
+        }
+    }
+    // POST lottery payouts to WEBHOOK_URL, if set
+    app.webhook = std::env::var("WEBHOOK_URL")
+        .ok()
+        .filter(|u| !u.is_empty())
+        .map(spawn_webhook);
+    if let Some(steps) = &scenario {
+        // The script alone drives the economy until the simulation is resumed
+        tracing::info!(
+            steps = steps.len(),
+            "scenario loaded, random simulation paused"
+        );
+        app.sim_paused = true;
+    }
+    let state: S = Arc::new(RwLock::new(app));
+
+    // Periodic save so a crash loses at most one interval. With
+    // JOURNAL_COMPACT the journal then keeps only what the save doesn't,
+    // which is all startup has to replay
+    if let Some(path) = state_path.clone() {
+        let saver = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if compact {
+                    let compacted = saver.read().await.compact_journal(path.clone());
+                    if let Err(e) = compacted.await {
+                        tracing::warn!(path = %path.display(), error = %e, "journal compaction failed");
+                    }
+                    continue;
+                }
+                // Copy under the lock, then write off the runtime without it
+                let persisted = saver.read().await.persisted();
+                let to = path.clone();
+                match tokio::task::spawn_blocking(move || persisted.save(&to)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        tracing::warn!(path = %path.display(), error = %e, "save failed")
+                    }
+                    Err(e) => tracing::warn!(error = %e, "save task failed"),
+                }
+            }
+        });
+    }
+
+    // Every change goes through one worker so they apply in the order they
+    // arrive. Background tasks wait for room rather than being turned away.
+    let (queue, commands) = mpsc::channel::<Command>(QUEUE_CAPACITY);
+    tokio::spawn(run_queue(state.clone(), commands));
+
+    // Subscribers read the state when they wake, so one notification at
+    // the end of each window delivers the last change of any burst
+    let notifier = queue.clone();
+    let dirty = state.read().await.dirty.clone();
+    tokio::spawn(async move {
+        let window = std::time::Duration::from_secs_f64(NOTIFY_WINDOW);
+        loop {
+            dirty.notified().await;
+            tokio::time::sleep(window).await;
+            if notifier.send(Box::new(App::publish)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    if let Some(steps) = scenario {
+        spawn_scenario(queue.clone(), steps);
+    }
+
+    // Snapshots only project the hot set, so settle the rest now and then
+    // to keep Koi's balance close to what it owes them
+    if state.read().await.cfg.hot_set.is_some() {
+        let sweeper = queue.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs_f64(COLD_SWEEP));
+            loop {
+                interval.tick().await;
+                if sweeper.send(Box::new(App::settle_cold)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let ready = Arc::new(AtomicBool::new(false));
+
+    // Random transactions, once per second by default
+    let sim_task = spawn_simulation(queue.clone(), sim_cfg);
+
+    // The state is loaded and the simulation spawned, so we can take traffic
+    ready.store(true, Ordering::Release);
+
+    let ws_cfg = WsCfg {
+        ping,
+        slots,
+        queue,
+        limiter,
+        token,
+    };
+
+    let app = router(state.clone(), ws_cfg, ready.clone(), cors);
+
+    let addr = format!("{}:{}", args.addr, args.port);
+    tracing::info!(%addr, "listening");
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let closer = state.clone();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        ready.store(false, Ordering::Release);
+        tracing::info!("shutdown: stopping simulation");
+        sim_task.abort();
+        // Swapping out the notifier closes every subscriber, which ends
+        // open /ws and /api/events streams so serve() can return
+        closer.write().await.notify = broadcast::channel(1).0;
+        tracing::info!("shutdown: waiting for in-flight requests");
+    })
+    .await
+    .unwrap();
+
+    let mut app = state.write().await;
+    if let (Some(journal), Some(task)) = (app.journal.take(), journal_task) {
+        // Dropping the last sender lets the writer drain and flush
+        drop(journal);
+        let _ = task.await;
+        tracing::info!("shutdown: journal flushed");
+    }
+    if let Some(path) = &state_path {
+        match app.save_to_path(path) {
+            Ok(()) => tracing::info!(path = %path.display(), "shutdown: state saved"),
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "shutdown: save failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+    use std::task::{Context, Waker};
+
+    /// A fresh economy of `n` wallets, its gifts drawn from `seed`.
+    fn app(n: usize, seed: u64) -> App {
+        app_with(Config::default(), n, seed)
+    }
+
+    fn app_with(cfg: Config, n: usize, seed: u64) -> App {
+        app_at(cfg, n, seed, Arc::new(SystemClock))
+    }
+
+    fn app_at(cfg: Config, n: usize, seed: u64, clock: Arc<dyn Clock>) -> App {
+        let notify = broadcast::channel(16).0;
+        App::new(cfg, n, notify, None, Some(seed), clock)
+    }
+
+    /// A fresh path under the temp dir, unique to this process and `name`.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("alice-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// A clock that only moves when told to.
+    struct MockClock(std::sync::Mutex<f64>);
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(MockClock(std::sync::Mutex::new(1_700_000_000.0)))
+        }
+
+        fn advance(&self, seconds: f64) {
+            *self.0.lock().unwrap() += seconds;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> f64 {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Give wallet `i` a known key and hand back its secret half.
+    fn key(app: &mut App, i: usize) -> SigningKey {
+        let key = SigningKey::from_bytes(&[i as u8; 32]);
+        app.wallets[i].pubkey = hex(key.verifying_key().as_bytes());
+        key
+    }
+
+    /// A send signed by `key` with the given nonce.
+    fn signed(key: &SigningKey, from: &str, to: &str, amount: Money, nonce: u64) -> SendReq {
+        let payload = signing_payload(from, to, amount, nonce);
+        SendReq {
+            from: from.into(),
+            to: to.into(),
+            amount,
+            idempotency_key: None,
+            nonce,
+            signature: Some(hex(&key.sign(payload.as_bytes()).to_bytes())),
+            dry_run: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_applies_a_flood_in_arrival_order() {
+        let mut a = app(20, 1);
+        let alice = key(&mut a, 1);
+        let s: S = Arc::new(RwLock::new(a));
+        let (queue, rx) = mpsc::channel::<Command>(QUEUE_CAPACITY);
+        // Hold the worker off so everything piles up before it runs
+        let held = s.read().await;
+        tokio::spawn(run_queue(s.clone(), rx));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut sends = Vec::new();
+        let mut marks = Vec::new();
+        for k in 0..200u64 {
+            let req = signed(&alice, "Alice", "Bob", Money::koi(k as i64 + 1), k + 1);
+            let mut send = Box::pin(enqueue(&queue, req));
+            // The first poll takes the request's place in line
+            assert!(send.as_mut().poll(&mut cx).is_pending());
+            sends.push(send);
+            if k % 20 == 0 {
+                let mut mark = Box::pin(apply(&queue, |app| app.next_id));
+                assert!(mark.as_mut().poll(&mut cx).is_pending());
+                marks.push((k, mark));
+            }
+        }
+        drop(held);
+        let mut ids = Vec::new();
+        for send in sends {
+            ids.push(send.await.unwrap().id.unwrap());
+        }
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        // Each change runs after the sends queued before it and before the rest
+        for (k, mark) in marks {
+            let next_id = mark.await.unwrap();
+            assert!(next_id > ids[k as usize]);
+            assert!(next_id <= ids[k as usize + 1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_turns_changes_away() {
+        let (queue, _rx) = mpsc::channel::<Command>(2);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut queued: Vec<_> = (0..2).map(|_| Box::pin(apply(&queue, |_| ()))).collect();
+        for q in &mut queued {
+            assert!(q.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(apply(&queue, |_| ()).await, Err(TxError::QueueFull));
+    }
+
+    #[test]
+    fn simulation_and_snapshots_stay_in_the_hot_set() {
+        let cfg = Config {
+            hot_set: Some(10),
+            ..Config::default()
+        };
+        let mut a = app_with(cfg, 50, 3);
+        let first = a.next_id;
+        for _ in 0..500 {
+            a.simulate(0.001..=0.01);
+        }
+        let hot: HashSet<&str> = a.wallets[..10].iter().map(|w| w.name.as_str()).collect();
+        let moved: Vec<&TxLog> = a.log.iter().filter(|tx| tx.id >= first).collect();
+        assert!(!moved.is_empty());
+        for tx in moved {
+            assert!(hot.contains(tx.from.as_str()) && hot.contains(tx.to.as_str()));
+        }
+        let snap = a.snapshot();
+        assert_eq!((snap.wallets.len(), snap.wallet_count), (10, 50));
+        a.publish();
+        assert_eq!(a.published.len(), 10);
+    }
+
+    #[test]
+    fn compressed_snapshot_is_a_fraction_of_the_plain_one() {
+        let a = app(100, 4);
+        let frame = Frame::Snapshot(Box::new(a.snapshot()));
+        let plain = frame.message(Wire::PLAIN).into_data().len();
+        let wire = Wire {
+            compress: Compress::Deflate,
+            ..Wire::PLAIN
+        };
+        let deflated = frame.message(wire).into_data().len();
+        assert!(deflated * 3 < plain);
+    }
+
+    #[test]
+    fn same_seed_gives_same_gifts_and_simulation() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 100, 7, clock.clone());
+        let mut b = app_at(Config::default(), 100, 7, clock.clone());
+        let holdings = |app: &App| -> Vec<(Money, Money)> {
+            app.wallets.iter().map(|w| (w.balance, w.locked)).collect()
+        };
+        assert_eq!(holdings(&a), holdings(&b));
+        let first = a.next_id;
+        for _ in 0..20 {
+            clock.advance(1.0);
+            a.simulate(0.001..=0.01);
+            b.simulate(0.001..=0.01);
+        }
+        let moves = |app: &App| -> Vec<(String, String, Money)> {
+            app.log
+                .iter()
+                .filter(|tx| tx.id >= first)
+                .map(|tx| (tx.from.clone(), tx.to.clone(), tx.amount))
+                .collect()
+        };
+        assert!(!moves(&a).is_empty());
+        assert_eq!(moves(&a), moves(&b));
+        assert_ne!(
+            holdings(&a),
+            holdings(&app_at(Config::default(), 100, 8, clock))
+        );
+    }
+
+    #[test]
+    fn log_keeps_the_newest_entries_up_to_its_cap() {
+        let cfg = Config {
+            log_cap: 25,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock);
+        for k in 1..=100 {
+            a.send(1, 2, Money::koi(k)).unwrap();
+            assert!(a.log.len() <= 25);
+        }
+        assert_eq!(a.log.len(), 25);
+        let ids: Vec<u64> = a.log.iter().map(|tx| tx.id).collect();
+        let newest: Vec<u64> = (a.next_id - 25..a.next_id).collect();
+        assert_eq!(ids, newest);
+        // Oldest first, as they were sent
+        let amounts: Vec<Money> = a.log.iter().map(|tx| tx.amount).collect();
+        assert_eq!(amounts, (76..=100).map(Money::koi).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn journal_has_one_line_per_transaction_in_order() {
+        let path = temp_path("journal.jsonl");
+        let (journal, task) = spawn_journal(&path).unwrap();
+        let notify = broadcast::channel(16).0;
+        let clock = MockClock::new();
+        let mut a = App::new(Config::default(), 20, notify, Some(journal), Some(1), clock);
+        for k in 1..=30 {
+            a.send(1 + k as usize % 3, 4, Money::koi(k)).unwrap();
+        }
+        let entry = |tx: &TxLog| (tx.id, tx.from.clone(), tx.to.clone(), tx.kind);
+        let logged: Vec<_> = a.log.iter().map(entry).collect();
+        // Dropping the App closes the channel, so the writer drains and stops
+        drop(a);
+        task.await.unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<_> = text
+            .lines()
+            .map(|l| entry(&serde_json::from_str(l).unwrap()))
+            .collect();
+        assert_eq!(lines, logged);
+    }
+
+    #[test]
+    fn name_index_follows_created_wallets() {
+        let mut a = app(20, 1);
+        let i = a.create_wallet("Zed".into(), false, None).unwrap();
+        let j = a.create_wallet("Pot".into(), true, None).unwrap();
+        assert_eq!((i, j), (20, 21));
+        assert_eq!(
+            a.create_wallet("Zed".into(), false, None),
+            Err(TxError::NameTaken)
+        );
+        assert_eq!(a.name_to_idx.len(), a.wallets.len());
+        for (k, w) in a.wallets.iter().enumerate() {
+            assert_eq!(a.index_of(&w.name), Some(k));
+        }
+        // Contracts track a contribution for the new wallets too
+        assert!(a.contracts.iter().all(|c| c.contributions.len() == 22));
+        a.send(1, i, Money::koi(10)).unwrap();
+        assert!(a.wallets[i].balance > Money::ZERO);
+    }
+
+    #[test]
+    fn settling_a_wallet_stamped_in_the_future_takes_nothing() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        let before = a.wallets[2].clone();
+        a.wallets[2].t = clock.now() + 3600.0;
+        a.settle(2);
+        let w = &a.wallets[2];
+        assert_eq!(
+            (w.balance, w.locked, w.vested),
+            (before.balance, before.locked, before.vested)
+        );
+        // Accrual picks up once the clock passes the stamp
+        clock.advance(7200.0);
+        a.settle(2);
+        assert!(a.wallets[2].balance > before.balance);
+    }
+
+    #[test]
+    fn koi_never_pays_out_more_than_it_holds() {
+        // Gifts leave Koi with about one Koi
+        let cfg = Config {
+            gift_rest: TOTAL_SUPPLY - GIFT_ALICE - Money::koi(1),
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 50, 1, clock.clone());
+        assert!(a.wallets[0].balance <= Money::koi(2));
+        for _ in 0..12 {
+            clock.advance(SPY / 12.0);
+            for i in 1..50 {
+                a.settle(i);
+            }
+            assert!(a.wallets[0].balance >= Money::ZERO);
+        }
+        assert!(a.invariant().ok);
+    }
+
+    #[test]
+    fn random_transfers_conserve_the_supply_exactly() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 50, 1, clock.clone());
+        let mut rng = StdRng::seed_from_u64(15);
+        for _ in 0..5000 {
+            clock.advance(rng.gen_range(0.0..600.0));
+            let from = rng.gen_range(1..50);
+            let to = rng.gen_range(1..50);
+            let amount = a.wallets[from].balance * rng.gen_range(0.0..1.2);
+            let _ = a.send(from, to, amount);
+            a.check_contracts();
+            assert_eq!(a.invariant().drift, Money::ZERO);
+        }
+        // And the state file keeps every nano-Koi
+        let path = temp_path("conserve.json");
+        a.save_to_path(&path).unwrap();
+        let notify = broadcast::channel(16).0;
+        let b =
+            App::load_from_path(&path, None, Config::default(), notify, None, None, clock).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let amounts = |app: &App| -> Vec<[Money; 6]> {
+            let w = app.wallets.iter();
+            w.map(|w| {
+                [
+                    w.balance, w.locked, w.vested, w.interest, w.sent, w.received,
+                ]
+            })
+            .collect()
+        };
+        assert_eq!(amounts(&a), amounts(&b));
+        assert_eq!(b.invariant().drift, Money::ZERO);
+    }
+
+    #[test]
+    fn ids_only_ever_go_up() {
+        let mut a = app(20, 1);
+        let mut ids = Vec::new();
+        for k in 1..=5 {
+            ids.push(a.send(1, 2, Money::koi(k)).unwrap().unwrap());
+        }
+        // Undoing takes the send out of the log, but its id stays spent
+        assert_eq!(a.undo(), Ok(ids[4]));
+        ids.push(a.send(1, 3, Money::koi(1)).unwrap().unwrap());
+        a.reset();
+        ids.push(a.send(1, 2, Money::koi(1)).unwrap().unwrap());
+        assert!(ids.windows(2).all(|w| w[0] < w[1]), "{ids:?}");
+        let logged: Vec<u64> = a.log.iter().map(|tx| tx.id).collect();
+        assert!(logged.windows(2).all(|w| w[0] < w[1]));
+        assert!(logged[0] > ids[5]);
+    }
+
+    #[test]
+    fn interest_entry_matches_what_was_credited() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        let (balance, koi) = (a.wallets[1].balance, a.wallets[0].balance);
+        clock.advance(86400.0);
+        a.settle(1);
+        let credited = a.wallets[1].balance - balance;
+        let entry = a.log.back().unwrap();
+        assert_eq!(
+            (entry.kind, entry.from.as_str(), entry.to.as_str()),
+            (TxKind::Interest, "Koi", "Alice")
+        );
+        assert!(credited >= INTEREST_LOG_MIN);
+        assert_eq!(entry.amount, credited);
+        assert_eq!(koi - a.wallets[0].balance, credited);
+    }
+
+    #[test]
+    fn configured_fee_rate_goes_to_koi() {
+        let cfg = Config {
+            fee_rate: 0.05,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let held = |w: &Wallet| w.balance + w.locked + w.vested;
+        let (alice, bob, koi) = (
+            held(&a.wallets[1]),
+            held(&a.wallets[2]),
+            a.wallets[0].balance,
+        );
+        a.send(1, 2, Money::koi(1000)).unwrap();
+        let fee = a.log.back().unwrap().fee;
+        assert_eq!(fee, Money::koi(50));
+        assert_eq!(a.wallets[0].balance - koi, fee);
+        assert_eq!(held(&a.wallets[2]) - bob, Money::koi(1000));
+        assert_eq!(alice - held(&a.wallets[1]), Money::koi(1050));
+    }
+
+    #[test]
+    fn non_finite_amounts_are_rejected_before_anything_runs() {
+        let mut a = app(20, 1);
+        let before = a.persisted();
+        for amount in ["1e999", "-1e999", "\"NaN\"", "\"inf\"", "null"] {
+            let body = format!(r#"{{"from":"Alice","to":"Bob","amount":{amount}}}"#);
+            assert!(serde_json::from_str::<SendReq>(&body).is_err(), "{amount}");
+        }
+        // Koi from an f64 that did slip through can't be NaN either
+        assert_eq!(Money::from_f64(f64::NAN), Money::ZERO);
+        assert_eq!(
+            a.send(1, 2, Money::from_f64(f64::NAN)),
+            Err(TxError::NonPositiveAmount)
+        );
+        assert!(a.wallets == before.wallets);
+        assert_eq!(a.next_id, before.next_id);
+    }
+
+    #[test]
+    fn received_and_tx_count_accumulate() {
+        let mut a = app_at(Config::default(), 20, 1, MockClock::new());
+        let (received, count) = (a.wallets[3].received, a.wallets[3].tx_count);
+        let sent_before = a.wallets[1].tx_count;
+        for k in 1..=4 {
+            a.send(1, 3, Money::koi(k * 10)).unwrap();
+        }
+        a.send(2, 3, Money::koi(5)).unwrap();
+        assert_eq!(a.wallets[3].received - received, Money::koi(105));
+        assert_eq!(a.wallets[3].tx_count - count, 5);
+        assert_eq!(a.wallets[1].tx_count - sent_before, 4);
+    }
+
+    /// An economy of `n` wallets whose last one is a lottery named Pot.
+    fn with_pot(n: usize, threshold: Money, cfg: Config) -> (App, Arc<MockClock>) {
+        let cfg = Config {
+            contracts: vec![ContractCfg {
+                name: "Pot".into(),
+                threshold,
+                share: 1.0,
+            }],
+            ..cfg
+        };
+        let clock = MockClock::new();
+        (app_at(cfg, n, 1, clock.clone()), clock)
+    }
+
+    #[test]
+    fn contracts_keep_their_own_contributions_and_payouts() {
+        let (mut a, _) = with_pot(20, Money::koi(100), Config::default());
+        let pot = a.index_of("Pot").unwrap();
+        a.send(4, MILLIONAIRE_IDX, Money::koi(30)).unwrap();
+        a.send(2, pot, Money::koi(60)).unwrap();
+        a.send(3, pot, Money::koi(60)).unwrap();
+        let (millionaire, pot_k) = (&a.contracts[0], &a.contracts[1]);
+        assert_eq!(millionaire.contributions[4], Money::koi(30));
+        assert_eq!(millionaire.contributions[2], Money::ZERO);
+        assert_eq!(pot_k.contributions[2], Money::koi(60));
+        a.check_contracts();
+        // Pot passed its threshold and paid out; Millionaire didn't
+        assert_eq!(a.wallets[pot].balance, Money::ZERO);
+        assert!(a.contracts[1]
+            .contributions
+            .iter()
+            .all(|&c| c == Money::ZERO));
+        assert_eq!(a.wallets[MILLIONAIRE_IDX].balance, Money::koi(30));
+        assert_eq!(a.contracts[0].contributions[4], Money::koi(30));
+        let payout = a.log.back().unwrap();
+        assert_eq!(
+            (payout.kind, payout.from.as_str()),
+            (TxKind::MillionairePayout, "Pot")
+        );
+    }
+
+    #[test]
+    fn carried_balance_counts_towards_the_next_payout() {
+        let (mut a, clock) = with_pot(20, Money::koi(100), Config::default());
+        let pot = a.index_of("Pot").unwrap();
+        a.contracts[1].share = 0.5;
+        a.send(2, pot, Money::koi(60)).unwrap();
+        a.send(3, pot, Money::koi(60)).unwrap();
+        a.check_contracts();
+        // Half went out, its fee on top
+        let carried = a.wallets[pot].balance;
+        assert!(Money::ZERO < carried && carried < Money::koi(60));
+        clock.advance(PAYOUT_COOLDOWN + 1.0);
+        a.send(2, pot, Money::koi(50)).unwrap();
+        a.send(4, pot, Money::koi(50)).unwrap();
+        a.check_contracts();
+        let pool = (carried + Money::koi(100)) * 0.5;
+        let payout = a.log.back().unwrap();
+        assert_eq!(payout.kind, TxKind::MillionairePayout);
+        assert_eq!(payout.amount, pool);
+    }
+
+    #[test]
+    fn cooldown_allows_one_payout() {
+        let cooldown = 60.0;
+        let cfg = Config {
+            payout_cooldown: cooldown,
+            ..Config::default()
+        };
+        let (mut a, clock) = with_pot(20, Money::koi(100), cfg);
+        let pot = a.index_of("Pot").unwrap();
+        let payouts = |a: &App| {
+            a.log
+                .iter()
+                .filter(|tx| tx.kind == TxKind::MillionairePayout)
+                .count()
+        };
+        for _ in 0..2 {
+            a.send(2, pot, Money::koi(60)).unwrap();
+            a.send(3, pot, Money::koi(60)).unwrap();
+            a.check_contracts();
+            clock.advance(cooldown / 4.0);
+        }
+        assert_eq!(payouts(&a), 1);
+        assert!(a.wallets[pot].balance > Money::koi(100));
+        // Once the cooldown is over the pending crossing pays
+        clock.advance(cooldown);
+        a.check_contracts();
+        assert_eq!(payouts(&a), 2);
+    }
+
+    #[test]
+    fn lone_contributor_waits_for_a_second() {
+        let cfg = Config {
+            min_contributors: 2,
+            ..Config::default()
+        };
+        let (mut a, _) = with_pot(20, Money::koi(100), cfg);
+        let pot = a.index_of("Pot").unwrap();
+        a.send(2, pot, Money::koi(150)).unwrap();
+        a.check_contracts();
+        assert!(a.wallets[pot].balance > Money::koi(100));
+        a.send(3, pot, Money::koi(1)).unwrap();
+        a.check_contracts();
+        assert_eq!(a.wallets[pot].balance, Money::ZERO);
+    }
+
+    #[test]
+    fn excluded_top_contributor_is_never_drawn() {
+        let cfg = Config {
+            exclude_top_contributor: true,
+            ..Config::default()
+        };
+        let (mut a, clock) = with_pot(20, Money::koi(100), cfg);
+        let pot = a.index_of("Pot").unwrap();
+        for _ in 0..30 {
+            a.send(2, pot, Money::koi(100)).unwrap();
+            a.send(3, pot, Money::koi(5)).unwrap();
+            a.send(4, pot, Money::koi(5)).unwrap();
+            a.check_contracts();
+            let payout = a.log.back().unwrap();
+            assert_eq!(payout.kind, TxKind::MillionairePayout);
+            assert_ne!(payout.to, a.wallets[2].name);
+            clock.advance(PAYOUT_COOLDOWN + 1.0);
+        }
+    }
+
+    #[test]
+    fn prize_split_pays_distinct_winners_their_shares() {
+        let cfg = Config {
+            fee_rate: 0.0,
+            prize_split: vec![0.5, 0.3, 0.2],
+            ..Config::default()
+        };
+        let (mut a, _) = with_pot(20, Money::koi(100), cfg);
+        let pot = a.index_of("Pot").unwrap();
+        for i in 2..5 {
+            a.send(i, pot, Money::koi(40)).unwrap();
+        }
+        a.check_contracts();
+        let payouts: Vec<_> = a.log.iter().rev().take(3).rev().collect();
+        assert!(payouts
+            .iter()
+            .all(|tx| tx.kind == TxKind::MillionairePayout));
+        let amounts: Vec<_> = payouts.iter().map(|tx| tx.amount).collect();
+        assert_eq!(amounts, [Money::koi(60), Money::koi(36), Money::koi(24)]);
+        let mut winners: Vec<_> = payouts.iter().map(|tx| tx.to.as_str()).collect();
+        winners.sort();
+        winners.dedup();
+        assert_eq!(winners.len(), 3);
+        assert_eq!(a.wallets[pot].balance, Money::ZERO);
+    }
+
+    #[test]
+    fn contributions_keep_the_decayed_weight_after_a_payout() {
+        let cfg = Config {
+            contribution_decay: 0.25,
+            ..Config::default()
+        };
+        let (mut a, _) = with_pot(20, Money::koi(100), cfg);
+        let pot = a.index_of("Pot").unwrap();
+        a.send(2, pot, Money::koi(80)).unwrap();
+        a.send(3, pot, Money::koi(40)).unwrap();
+        a.check_contracts();
+        assert_eq!(a.wallets[pot].balance, Money::ZERO);
+        let left = &a.contracts[1].contributions;
+        assert_eq!(left[2], Money::koi(20));
+        assert_eq!(left[3], Money::koi(10));
+    }
+
+    #[test]
+    fn unstaking_after_a_year_returns_the_stake_with_its_reward() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        a.send(2, STAKING_IDX, Money::koi(100)).unwrap();
+        let principal = a.stakes[0].amount;
+        clock.advance(SPY);
+        let id = a.unstake(2).unwrap();
+        let reward = Money::from_f64(principal.to_f64() * (STAKE_RATE.exp() - 1.0));
+        assert!(reward > Money::koi(5));
+        let entry = a.log.iter().find(|tx| tx.id == id).unwrap();
+        assert_eq!(entry.kind, TxKind::Unstake);
+        assert_eq!(entry.amount, principal + reward);
+        assert!(a.stakes.is_empty());
+        assert_eq!(a.unstake(2), Err(TxError::NothingStaked));
+    }
+
+    #[test]
+    fn burning_shrinks_the_supply_without_feeding_koi() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock);
+        let (balance, koi) = (a.wallets[2].balance, a.wallets[0].balance);
+        a.burn(2, Money::koi(10)).unwrap();
+        assert_eq!(a.wallets[2].balance, balance - Money::koi(10));
+        assert_eq!(a.burned, Money::koi(10));
+        assert_eq!(a.wallets[0].balance, koi);
+        assert!(a.invariant().ok);
+        assert_eq!(a.burn(2, balance), Err(TxError::InsufficientBalance));
+    }
+
+    #[test]
+    fn interest_halves_after_a_halving() {
+        let cfg = Config {
+            halving_interval: 10.0 * 86400.0,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        let hour = |a: &mut App| {
+            a.settle(2);
+            let w = &a.wallets[2];
+            let (held, before) = ((w.balance + w.vested).to_f64(), w.interest);
+            clock.advance(3600.0);
+            a.settle(2);
+            // Vesting grows what earns, so compare interest per Koi held
+            (a.wallets[2].interest - before).to_f64() / held
+        };
+        let early = hour(&mut a);
+        clock.advance(10.0 * 86400.0);
+        let late = hour(&mut a);
+        assert!(early > 0.0);
+        assert!((late / early - 0.5).abs() < 0.01, "{late} / {early}");
+    }
+
+    #[test]
+    fn nothing_vests_inside_the_cliff() {
+        let cfg = Config {
+            vesting_cliff: 3600.0,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        let locked = a.wallets[3].locked;
+        assert!(locked > Money::ZERO);
+        clock.advance(1800.0);
+        a.settle(3);
+        assert_eq!(a.wallets[3].vested, Money::ZERO);
+        assert_eq!(a.wallets[3].locked, locked);
+        // Past the cliff it vests from the last settle as usual
+        clock.advance(5400.0);
+        a.settle(3);
+        let vested = Money::from_f64(locked.to_f64() * ((PRATE * 5400.0 / SPY).exp() - 1.0));
+        assert_eq!(a.wallets[3].vested, vested);
+        assert!(a.wallets[3].cliff.is_empty());
+    }
+
+    #[test]
+    fn invariant_holds_through_every_kind_of_change() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 30, 1, clock.clone());
+        let mut rng = StdRng::seed_from_u64(43);
+        for _ in 0..3000 {
+            clock.advance(rng.gen_range(0.0..3600.0));
+            let i = rng.gen_range(1..30);
+            let amount = a.wallets[i].balance * rng.gen_range(0.0..0.5);
+            let _ = match rng.gen_range(0..7) {
+                0 => a.send(i, i, amount).map(|_| ()),
+                1 => a.send(i, STAKING_IDX, amount).map(|_| ()),
+                2 => a.unstake(i).map(|_| ()),
+                3 => a.burn(i, amount).map(|_| ()),
+                4 => a.mint(Money::koi(rng.gen_range(1..1000))).map(|_| ()),
+                5 => a.send(i, MILLIONAIRE_IDX, amount).map(|_| ()),
+                _ => a.send(i, rng.gen_range(1..30), amount).map(|_| ()),
+            };
+            a.check_contracts();
+            let inv = a.invariant();
+            assert!(inv.ok, "drifted by {:?}", inv.drift);
+        }
+        assert!(a.burned > Money::ZERO && a.minted > Money::ZERO);
+    }
+
+    #[test]
+    fn repeated_idempotency_key_sends_once() {
+        let mut a = app(20, 1);
+        let alice = key(&mut a, 1);
+        let req = || SendReq {
+            idempotency_key: Some("retry-1".into()),
+            ..signed(&alice, "Alice", "Bob", Money::koi(10), 1)
+        };
+        let first = a.submit(req()).unwrap();
+        let next_id = a.next_id;
+        let again = a.submit(req()).unwrap();
+        assert_eq!(a.next_id, next_id);
+        assert_eq!(a.wallets[1].nonce, 1);
+        let json = |res: &SendRes| serde_json::to_value(res).unwrap();
+        assert_eq!(json(&first), json(&again));
+    }
+
+    #[test]
+    fn dust_send_is_refused_and_changes_nothing() {
+        let mut a = app(20, 1);
+        let (wallets, next_id) = (a.wallets.clone(), a.next_id);
+        let dust = MIN_TX_AMOUNT - Money::from_f64(0.5);
+        assert_eq!(a.send(2, 3, dust), Err(TxError::BelowMinimum));
+        assert!(a.wallets == wallets);
+        assert_eq!(a.next_id, next_id);
+        // Gifts from Koi are exempt
+        assert!(a.send(0, 3, dust).is_ok());
+    }
+
+    #[test]
+    fn large_share_of_the_balance_pays_the_higher_tier() {
+        let cfg = Config {
+            fee_tiers: vec![FeeTier {
+                share: 0.5,
+                rate: 0.01,
+            }],
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let fee_on = |share: f64| {
+            let mut a = app_at(cfg.clone(), 20, 1, clock.clone());
+            let amount = a.wallets[2].balance * share;
+            a.send(2, 3, amount).unwrap();
+            (amount, a.log.back().unwrap().fee)
+        };
+        let (small, small_fee) = fee_on(0.1);
+        let (large, large_fee) = fee_on(0.6);
+        assert_eq!(small_fee, small * FEE_RATE);
+        assert_eq!(large_fee, large * 0.01);
+        assert!(large_fee.to_f64() / large.to_f64() > 5.0 * small_fee.to_f64() / small.to_f64());
+    }
+
+    /// `app` behind the full router on a free local port, its queue worker
+    /// running, with `tweak` applied to the defaults main would use.
+    /// Returns the base URL and the state.
+    async fn serve(app: App, tweak: impl FnOnce(&mut WsCfg)) -> (String, S) {
+        let state: S = Arc::new(RwLock::new(app));
+        let (queue, commands) = mpsc::channel::<Command>(QUEUE_CAPACITY);
+        tokio::spawn(run_queue(state.clone(), commands));
+        let limiter = RateLimiter {
+            rate: 5.0,
+            burst: 20.0,
+            buckets: HashMap::new(),
+        };
+        let mut ws_cfg = WsCfg {
+            ping: std::time::Duration::from_secs(30),
+            slots: WsSlots {
+                open: Arc::default(),
+                max: 64,
+            },
+            queue,
+            limiter: Arc::new(std::sync::Mutex::new(limiter)),
+            token: None,
+        };
+        tweak(&mut ws_cfg);
+        let ready = Arc::new(AtomicBool::new(true));
+        let routes = router(state.clone(), ws_cfg, ready, CorsLayer::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let service = routes.into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+        (url, state)
+    }
+
+    #[tokio::test]
+    async fn sends_past_the_burst_get_429() {
+        let (url, _) = serve(app(20, 1), |ws| {
+            ws.limiter.lock().unwrap().burst = 3.0;
+            ws.limiter.lock().unwrap().rate = 0.01;
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let body = serde_json::json!({"from": "Nobody", "to": "Bob", "amount": 1.0});
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            let res = client.post(format!("{url}/api/send")).json(&body);
+            statuses.push(res.send().await.unwrap().status().as_u16());
+        }
+        // The first three are let through to fail on their own merits
+        assert_eq!(statuses, [404, 404, 404, 429, 429]);
+    }
+
+    #[tokio::test]
+    async fn mutating_routes_need_the_right_token() {
+        let (url, state) = serve(app(20, 1), |ws| ws.token = Some("s3cret".into())).await;
+        let client = reqwest::Client::new();
+        let pause = |auth: Option<&str>| {
+            let req = client.post(format!("{url}/api/sim/pause"));
+            match auth {
+                Some(auth) => req.header(header::AUTHORIZATION, auth),
+                None => req,
+            }
+            .send()
+        };
+        assert_eq!(
+            pause(None).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let wrong = pause(Some("Bearer guess")).await.unwrap();
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        assert!(!state.read().await.sim_paused);
+        let right = pause(Some("Bearer s3cret")).await.unwrap();
+        assert_eq!(right.status(), StatusCode::OK);
+        assert!(state.read().await.sim_paused);
+        // Reads stay open
+        let stats = client.get(format!("{url}/api/stats")).send().await.unwrap();
+        assert_eq!(stats.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn tampered_signature_is_rejected() {
+        let mut a = app(20, 1);
+        let alice = key(&mut a, 1);
+        let forged = SendReq {
+            amount: Money::koi(1000),
+            ..signed(&alice, "Alice", "Bob", Money::koi(10), 1)
+        };
+        assert_eq!(a.submit(forged).err(), Some(TxError::BadSignature));
+        let mut req = signed(&alice, "Alice", "Bob", Money::koi(10), 1);
+        let sig = req.signature.as_mut().unwrap();
+        let flipped = if sig.starts_with('0') { "1" } else { "0" };
+        sig.replace_range(..1, flipped);
+        assert_eq!(a.submit(req).err(), Some(TxError::BadSignature));
+        // Someone else's key can't sign for Alice either
+        let mallory = SigningKey::from_bytes(&[99; 32]);
+        let req = signed(&mallory, "Alice", "Bob", Money::koi(10), 1);
+        assert_eq!(a.submit(req).err(), Some(TxError::BadSignature));
+        assert_eq!(a.wallets[1].nonce, 0);
+        let res = a.submit(signed(&alice, "Alice", "Bob", Money::koi(10), 1));
+        assert!(res.unwrap().ok);
+    }
+
+    #[test]
+    fn stale_nonce_is_rejected() {
+        let mut a = app(20, 1);
+        let alice = key(&mut a, 1);
+        a.submit(signed(&alice, "Alice", "Bob", Money::koi(10), 1))
+            .unwrap();
+        // Replaying the same request, or skipping ahead, both fail
+        let replay = signed(&alice, "Alice", "Bob", Money::koi(10), 1);
+        assert_eq!(a.submit(replay).err(), Some(TxError::BadNonce));
+        let skipped = signed(&alice, "Alice", "Bob", Money::koi(10), 3);
+        assert_eq!(a.submit(skipped).err(), Some(TxError::BadNonce));
+        assert!(a
+            .submit(signed(&alice, "Alice", "Bob", Money::koi(10), 2))
+            .is_ok());
+        assert_eq!(a.wallets[1].nonce, 2);
+    }
+
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    /// Open `/ws` on the server at `url`, taking the default JSON text frames.
+    async fn connect(url: &str) -> Socket {
+        let url = format!("{}/ws", url.replacen("http", "ws", 1));
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    /// The next JSON frame from `sock`.
+    async fn frame(sock: &mut Socket) -> serde_json::Value {
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
         loop {
-            interval.tick().await;
-            let mut app = sim.lock().await;
-            let n = app.wallets.len();
-            // Pick from excluding Koi (0), Alice (1), and Millionaire (6)
-            let mut from = app.rng.gen_range(2..n - 1); // n-1 candidates (skip one)
-            if from >= MILLIONAIRE_IDX {
-                from += 1; // skip over Millionaire
-            }
-            // 50% chance target is Millionaire, otherwise random wallet
-            let to = if app.rng.gen_bool(0.5) {
-                MILLIONAIRE_IDX
-            } else {
-                let mut t = app.rng.gen_range(2..n - 2); // exclude Millionaire and from
-                if t >= MILLIONAIRE_IDX.min(from) {
-                    t += 1;
+            match sock.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => return serde_json::from_str(&text).unwrap(),
+                WsMessage::Close(_) => panic!("socket closed"),
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn lagging_subscriber_resyncs_from_a_snapshot() {
+        let notify = broadcast::channel(1).0;
+        let a = App::new(
+            Config::default(),
+            20,
+            notify,
+            None,
+            Some(1),
+            MockClock::new(),
+        );
+        let (url, state) = serve(a, |_| {}).await;
+        let mut sock = connect(&url).await;
+        assert_eq!(frame(&mut sock).await["type"], "snapshot");
+        // The connection can't read the state while we hold it, so it
+        // falls behind a one-slot channel
+        {
+            let mut app = state.write().await;
+            for _ in 0..5 {
+                app.send(2, 3, Money::koi(1)).unwrap();
+                app.publish();
+            }
+        }
+        let snap = loop {
+            let f = frame(&mut sock).await;
+            if f["type"] == "snapshot" {
+                break f;
+            }
+        };
+        // It carries everything the skipped updates would have
+        let app = state.read().await;
+        let last = snap["log"].as_array().unwrap().last().unwrap();
+        assert_eq!(last["id"], app.next_id - 1);
+        let lag = &app.lag;
+        assert!(lag.events.load(Ordering::Relaxed) >= 1);
+        assert!(lag.skipped.load(Ordering::Relaxed) >= 1);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_fifty_clients_per_update() {
+        let a = app(500, 1);
+        let enc = Wire::default();
+        let rounds = 10;
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            // Every client building and encoding its own snapshot
+            for _ in 0..50 {
+                std::hint::black_box(Frame::Snapshot(Box::new(a.snapshot())).message(enc));
+            }
+        }
+        let each = start.elapsed();
+        let start = std::time::Instant::now();
+        for _ in 0..rounds {
+            let shared = Shared::new(Frame::Snapshot(Box::new(a.snapshot())));
+            for _ in 0..50 {
+                std::hint::black_box(shared.message(enc));
+            }
+        }
+        let once = start.elapsed();
+        println!("50 clients x {rounds} updates: {each:?} encoding per client, {once:?} shared");
+        assert!(once * 10 < each);
+    }
+
+    #[tokio::test]
+    async fn reads_run_alongside_each_other() {
+        let (url, state) = serve(app(20, 1), |_| {}).await;
+        let client = reqwest::Client::new();
+        // A reader holding the state mustn't keep two more from answering
+        let _held = state.read().await;
+        let get = |path: &str| client.get(format!("{url}{path}")).send();
+        let both = async { tokio::join!(get("/api/log"), get("/api/invariant")) };
+        let timeout = std::time::Duration::from_secs(5);
+        let (log, invariant) = tokio::time::timeout(timeout, both).await.unwrap();
+        assert_eq!(log.unwrap().status(), StatusCode::OK);
+        assert_eq!(invariant.unwrap().status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn msgpack_and_json_frames_carry_the_same_snapshot() {
+        use std::io::Read;
+        let a = app(20, 1);
+        let frame = Frame::Snapshot(Box::new(a.snapshot()));
+        let wire = |format, compress| Wire { format, compress };
+        assert!(matches!(frame.message(Wire::PLAIN), Message::Text(_)));
+        // Straight from the frame, as parsing the text can be a bit off in
+        // the last digit
+        let json = serde_json::to_value(&frame).unwrap();
+        let packed = match frame.message(wire(Encoding::Msgpack, Compress::None)) {
+            Message::Binary(bytes) => bytes,
+            _ => panic!("MessagePack goes out as binary"),
+        };
+        let unpacked: serde_json::Value = rmp_serde::from_slice(&packed).unwrap();
+        assert_eq!(unpacked, json);
+        assert_eq!(json["type"], "snapshot");
+        // Compression wraps the same bytes
+        let deflated = frame
+            .message(wire(Encoding::Msgpack, Compress::Deflate))
+            .into_data();
+        let mut inflated = Vec::new();
+        flate2::read::ZlibDecoder::new(&deflated[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, packed);
+    }
+
+    #[tokio::test]
+    async fn payout_webhook_posts_the_winner() {
+        // A receiver that fails its first delivery, to be retried
+        let (seen, mut deliveries) = mpsc::unbounded_channel::<serde_json::Value>();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let mock = Router::new().route(
+            "/hook",
+            post(move |Json(body): Json<serde_json::Value>| async move {
+                let _ = seen.send(body);
+                match hits.fetch_add(1, Ordering::Relaxed) {
+                    0 => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::OK,
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, mock).await });
+
+        let (mut a, _) = with_pot(20, Money::koi(100), Config::default());
+        a.webhook = Some(spawn_webhook(url));
+        let pot = a.index_of("Pot").unwrap();
+        a.send(2, pot, Money::koi(60)).unwrap();
+        a.send(3, pot, Money::koi(60)).unwrap();
+        a.check_contracts();
+        let payout = a.log.back().unwrap().clone();
+
+        let first = deliveries.recv().await.unwrap();
+        let retried = deliveries.recv().await.unwrap();
+        assert_eq!(first, retried);
+        assert_eq!(first["winner"], payout.to.as_str());
+        assert_eq!(first["contract"], "Pot");
+        assert_eq!(
+            first["amount"],
+            serde_json::to_value(payout.amount).unwrap()
+        );
+        assert_eq!(first["t"], payout.t);
+    }
+
+    #[tokio::test]
+    async fn new_wallet_can_be_sent_to() {
+        let mut a = app(20, 1);
+        let alice = key(&mut a, 1);
+        let req = signed(&alice, "Alice", "Zed", Money::koi(30), 1);
+        let (url, state) = serve(a, |_| {}).await;
+        let client = reqwest::Client::new();
+        let created = client
+            .post(format!("{url}/api/wallet"))
+            .json(&serde_json::json!({"name": "Zed"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let body = serde_json::json!({
+            "from": req.from,
+            "to": req.to,
+            "amount": req.amount,
+            "nonce": req.nonce,
+            "signature": req.signature,
+        });
+        let sent = client.post(format!("{url}/api/send")).json(&body).send();
+        assert_eq!(sent.await.unwrap().status(), StatusCode::OK);
+        let app = state.read().await;
+        let zed = &app.wallets[app.index_of("Zed").unwrap()];
+        assert_eq!(zed.received, Money::koi(30));
+        assert_eq!(zed.balance + zed.locked + zed.vested, Money::koi(30));
+    }
+
+    #[test]
+    fn frozen_wallet_neither_sends_nor_receives() {
+        let mut a = app(20, 1);
+        a.set_frozen(3, true).unwrap();
+        let wallets = a.wallets.clone();
+        assert_eq!(a.send(3, 2, Money::koi(5)), Err(TxError::Frozen));
+        assert_eq!(a.send(2, 3, Money::koi(5)), Err(TxError::Frozen));
+        assert_eq!(a.burn(3, Money::koi(5)), Err(TxError::Frozen));
+        assert!(a.wallets == wallets);
+        assert_eq!(a.set_frozen(0, true), Err(TxError::Unfreezable));
+        a.set_frozen(3, false).unwrap();
+        assert!(a.send(2, 3, Money::koi(5)).is_ok());
+    }
+
+    #[test]
+    fn apy_follows_koi_balance() {
+        let gifts = [("Alice", 100_000_000), ("Bob", 400_000_000)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        // Half the supply left in Koi earns half the base rate, compounded
+        assert_eq!(a.wallets[0].balance, Money::koi(500_000_000));
+        let apy = a.snapshot().apy;
+        assert!((apy - (RATE / 2.0).exp_m1()).abs() < 1e-12, "{apy}");
+        assert!((apy - 0.2034).abs() < 1e-4);
+        assert_eq!(a.wallet_res(2).apy, apy);
+        // A wallet's own multiplier compounds on top
+        a.set_rate_multiplier(2, 2.0).unwrap();
+        let doubled = a.wallet_res(2).apy;
+        assert!((doubled - RATE.exp_m1()).abs() < 1e-12, "{doubled}");
+        assert!((doubled - ((1.0 + apy).powi(2) - 1.0)).abs() < 1e-12);
+        assert_eq!(a.wallet_res(0).apy, 0.0);
+    }
+
+    #[test]
+    fn a_year_on_the_mock_clock_earns_a_years_interest() {
+        let gifts = [("Alice", 100_000_000), ("Bob", 400_000_000)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        clock.advance(SPY);
+        a.settle(2);
+        // Koi holds half the supply, so Bob earns half the base rate
+        let expected = Money::from_f64(400_000_000.0 * ((RATE / 2.0).exp() - 1.0));
+        assert_eq!(a.wallets[2].interest, expected);
+        assert_eq!(a.wallets[2].balance, Money::koi(400_000_000) + expected);
+        assert_eq!(a.wallets[0].balance, Money::koi(500_000_000) - expected);
+    }
+
+    #[tokio::test]
+    async fn tiny_sim_interval_makes_many_transactions() {
+        let s: S = Arc::new(RwLock::new(app(20, 1)));
+        let first = s.read().await.next_id;
+        let (queue, commands) = mpsc::channel::<Command>(QUEUE_CAPACITY);
+        tokio::spawn(run_queue(s.clone(), commands));
+        let sim = SimCfg {
+            interval: std::time::Duration::from_millis(1),
+            min_share: 0.001,
+            max_share: 0.01,
+        };
+        let task = spawn_simulation(queue, sim);
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        task.abort();
+        let app = s.read().await;
+        let sends = app
+            .log
+            .iter()
+            .filter(|tx| tx.id >= first && tx.kind == TxKind::Transfer)
+            .count();
+        // Some 500 ticks, with room for a busy machine
+        assert!(sends >= 50, "only {sends} sends");
+    }
+
+    #[tokio::test]
+    async fn scenario_steps_run_in_order() {
+        let gifts = [("Alice", 1000), ("Bob", 500)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            fee_rate: 0.0,
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let s: S = Arc::new(RwLock::new(app_at(cfg, 20, 1, MockClock::new())));
+        let path = temp_path("scenario.csv");
+        let script = "from,to,amount,delay_ms\nAlice,Bob,300,0\nBob,Carol,700,10\n";
+        std::fs::write(&path, script).unwrap();
+        let steps = load_scenario(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let (queue, commands) = mpsc::channel::<Command>(QUEUE_CAPACITY);
+        tokio::spawn(run_queue(s.clone(), commands));
+        spawn_scenario(queue.clone(), steps).await.unwrap();
+        // Bob can only afford the second step with what the first sent
+        let balances = apply(&queue, |app| [1, 2, 3].map(|i| app.wallets[i].balance))
+            .await
+            .unwrap();
+        assert_eq!(balances, [700, 100, 700].map(Money::koi));
+    }
+
+    #[test]
+    fn early_settle_logs_the_fee_it_charges() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        clock.advance(86400.0);
+        a.settle(3);
+        let (koi, locked, vested) = (
+            a.wallets[0].balance,
+            a.wallets[3].locked,
+            a.wallets[3].vested,
+        );
+        assert!(vested > Money::ZERO);
+        let amount = Money::koi(1000);
+        a.send(3, 3, amount).unwrap();
+        // The claim and the withdrawal are logged apart
+        let n = a.log.len();
+        let (claim, early) = (&a.log[n - 2], &a.log[n - 1]);
+        assert_eq!(
+            (claim.kind, claim.amount, claim.fee),
+            (TxKind::Settle, vested, Money::ZERO)
+        );
+        assert_eq!((early.kind, early.amount), (TxKind::EarlySettle, amount));
+        let charged = a.wallets[0].balance - koi;
+        assert_eq!(early.fee, charged);
+        assert_eq!(early.fee, amount * EARLY_FEE);
+        assert_eq!(a.wallets[3].locked, locked - amount - charged);
+    }
+
+    #[test]
+    fn uncovered_fee_comes_out_of_the_transfer() {
+        let cfg = Config {
+            gifts: Some([("Bob".into(), Money::koi(100))].into()),
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let koi = a.wallets[0].balance;
+        // Bob has the amount and nothing left over for the fee
+        a.send(2, 3, Money::koi(100)).unwrap();
+        let fee = Money::koi(100) * FEE_RATE;
+        let entry = a.log.back().unwrap();
+        assert_eq!((entry.amount, entry.fee), (Money::koi(100) - fee, fee));
+        assert_eq!(a.wallets[2].balance, Money::ZERO);
+        assert_eq!(a.wallets[3].balance, Money::koi(100) - fee);
+        assert_eq!(a.wallets[0].balance, koi + fee);
+        assert_eq!(a.invariant().drift, Money::ZERO);
+    }
+
+    #[tokio::test]
+    async fn probes_answer() {
+        let (url, state) = serve(app(20, 1), |_| {}).await;
+        let client = reqwest::Client::new();
+        // Liveness holds up even with the state locked
+        let held = state.write().await;
+        let health = client.get(format!("{url}/healthz")).send().await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+        let ready = client.get(format!("{url}/readyz")).send().await.unwrap();
+        assert_eq!(ready.status(), StatusCode::OK);
+        drop(held);
+        let (status, _) = readyz_handler(Arc::new(AtomicBool::new(false))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn odds_sum_to_one_in_proportion() {
+        let mut a = app(20, 1);
+        for (i, k) in [(2, 10), (3, 30), (4, 60)] {
+            a.send(i, MILLIONAIRE_IDX, Money::koi(k)).unwrap();
+        }
+        let odds = &a.odds()[0];
+        assert_eq!(odds.total, Money::koi(100));
+        let total: f64 = odds.contributors.iter().map(|o| o.probability).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        let p: Vec<f64> = odds.contributors.iter().map(|o| o.probability).collect();
+        assert_eq!(p, [0.1, 0.3, 0.6]);
+    }
+
+    #[test]
+    fn interest_scales_with_the_rate_multiplier() {
+        let gifts = [("Bob", 1_000_000), ("Carol", 1_000_000)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        a.set_rate_multiplier(3, 2.0).unwrap();
+        clock.advance(3600.0);
+        a.settle(2);
+        a.settle(3);
+        // Over an hour e^x - 1 is as good as linear
+        let ratio = a.wallets[3].interest.to_f64() / a.wallets[2].interest.to_f64();
+        assert!((ratio - 2.0).abs() < 1e-3, "{ratio}");
+        assert_eq!(a.set_rate_multiplier(0, 2.0), Err(TxError::NoInterest));
+    }
+
+    #[tokio::test]
+    async fn settling_after_a_day_credits_interest() {
+        let clock = MockClock::new();
+        let (url, state) = serve(app_at(Config::default(), 20, 1, clock.clone()), |_| {}).await;
+        let client = reqwest::Client::new();
+        let before = state.read().await.wallets[2].clone();
+        clock.advance(86400.0);
+        let res = client
+            .post(format!("{url}/api/settle/Bob"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = res.json().await.unwrap();
+        let after = state.read().await.wallets[2].clone();
+        assert!(after.interest > before.interest);
+        assert_eq!(
+            after.balance,
+            before.balance + after.interest - before.interest
+        );
+        assert_eq!(body["t"], clock.now());
+        assert_eq!(
+            body["interest"],
+            serde_json::to_value(after.interest).unwrap()
+        );
+        let koi = client
+            .post(format!("{url}/api/settle/Koi"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(koi.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn only_the_first_transfer_pays_the_referral() {
+        let cfg = Config {
+            referral: true,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let referrals = |a: &App| {
+            a.log
+                .iter()
+                .filter(|tx| tx.kind == TxKind::Referral)
+                .count()
+        };
+        a.send(2, 3, Money::koi(10)).unwrap();
+        assert_eq!(referrals(&a), 1);
+        let bonus = a.log.back().unwrap();
+        assert_eq!((bonus.to.as_str(), bonus.amount), ("Bob", REFERRAL_BONUS));
+        // Carol has been referred, by Bob or anyone else
+        a.send(2, 3, Money::koi(10)).unwrap();
+        a.send(4, 3, Money::koi(10)).unwrap();
+        assert_eq!(referrals(&a), 1);
+    }
+
+    #[test]
+    fn leaderboard_ranks_by_holdings() {
+        let gifts = [("Alice", 100), ("Bob", 200), ("Carol", 300), ("Dan", 50)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            fee_rate: 0.0,
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        a.send(3, 4, Money::koi(280)).unwrap();
+        a.send(2, 1, Money::koi(150)).unwrap();
+        let top = a.leaderboard(4);
+        let names: Vec<_> = top.iter().map(|r| (r.rank, r.name.as_str())).collect();
+        assert_eq!(names, [(1, "Dan"), (2, "Alice"), (3, "Bob"), (4, "Carol")]);
+        let holdings: Vec<_> = top.iter().map(|r| r.holdings).collect();
+        assert_eq!(holdings, [330, 250, 50, 20].map(Money::koi));
+        // Koi and the contracts never rank
+        assert_eq!(a.leaderboard(1000).len(), 17);
+    }
+
+    #[test]
+    fn gini_is_zero_for_equal_holdings() {
+        assert_eq!(gini(&[Money::koi(5); 10]), 0.0);
+        // One holder with everything among four: (n - 1) / n
+        let mut lopsided = [Money::ZERO; 4];
+        lopsided[3] = Money::koi(100);
+        assert!((gini(&lopsided) - 0.75).abs() < 1e-12);
+        assert_eq!(gini(&[]), 0.0);
+        // And through the stats of an economy split evenly
+        let names = ["Alice", "Bob", "Carol", "Dan", "Eve"];
+        let cfg = Config {
+            gifts: Some(names.map(|n| (n.into(), Money::koi(1000))).into()),
+            ..Config::default()
+        };
+        let a = app_at(cfg, NAMED.len(), 1, MockClock::new());
+        assert!(a.stats().gini.abs() < 1e-12);
+    }
+
+    #[test]
+    fn undo_puts_both_wallets_back_exactly() {
+        let cfg = Config {
+            referral: true,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        clock.advance(3600.0);
+        a.settle(2);
+        a.settle(3);
+        let (wallets, fees) = (a.wallets.clone(), a.fees_collected);
+        let id = a.send(2, 3, Money::koi(250)).unwrap().unwrap();
+        assert!(a.wallets[3].referred);
+        assert_eq!(a.undo(), Ok(id));
+        assert!(a.wallets == wallets);
+        assert_eq!(a.fees_collected, fees);
+        assert_eq!(a.log.back().unwrap().kind, TxKind::Undo);
+        assert_eq!(a.undo(), Err(TxError::NothingToUndo));
+    }
+
+    #[test]
+    fn recipient_split_follows_the_config() {
+        let cfg = Config {
+            fee_rate: 0.0,
+            liquid_share: 0.25,
+            locked_share: 0.75,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let (balance, locked) = (a.wallets[3].balance, a.wallets[3].locked);
+        a.send(2, 3, Money::koi(400)).unwrap();
+        assert_eq!(a.wallets[3].balance - balance, Money::koi(100));
+        assert_eq!(a.wallets[3].locked - locked, Money::koi(300));
+        // Contracts take it all liquid
+        a.send(2, MILLIONAIRE_IDX, Money::koi(400)).unwrap();
+        assert_eq!(a.wallets[MILLIONAIRE_IDX].balance, Money::koi(400));
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        let alice = key(&mut a, 1);
+        let mut rx = a.notify.subscribe();
+        // Enough time for the preview's settle to have something to do
+        clock.advance(86400.0);
+        let (wallets, next_id, log) = (a.wallets.clone(), a.next_id, a.log.len());
+        let req = SendReq {
+            dry_run: true,
+            ..signed(&alice, "Alice", "Bob", Money::koi(100), 1)
+        };
+        let res = a.submit(req).unwrap();
+        assert_eq!(res.id, None);
+        assert_eq!(res.fee, Some(Money::koi(100) * FEE_RATE));
+        assert!(a.wallets == wallets);
+        assert_eq!((a.next_id, a.log.len()), (next_id, log));
+        assert!(rx.try_recv().is_err());
+        // The same request can still go through for real
+        let req = signed(&alice, "Alice", "Bob", Money::koi(100), 1);
+        assert!(a.submit(req).unwrap().id.is_some());
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_compressed() {
+        let (url, _) = serve(app(200, 1), |_| {}).await;
+        // Without reqwest's gzip feature the body arrives as sent
+        let client = reqwest::Client::new();
+        let get = |path: &str, encoding: &str| {
+            let req = client.get(format!("{url}{path}"));
+            req.header(header::ACCEPT_ENCODING, encoding).send()
+        };
+        let log = get("/api/log", "gzip").await.unwrap();
+        assert_eq!(log.headers()[header::CONTENT_ENCODING], "gzip");
+        let plain = get("/api/log", "identity").await.unwrap();
+        assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+        let (zipped, plain) = (log.bytes().await.unwrap(), plain.bytes().await.unwrap());
+        assert!(zipped.len() * 3 < plain.len());
+    }
+
+    #[test]
+    fn new_threshold_governs_the_payout() {
+        let (mut a, _) = with_pot(20, Money::koi(100), Config::default());
+        let pot = a.index_of("Pot").unwrap();
+        a.configure_contract(pot, Some(Money::koi(500)), None)
+            .unwrap();
+        a.send(2, pot, Money::koi(150)).unwrap();
+        a.send(3, pot, Money::koi(150)).unwrap();
+        a.check_contracts();
+        // Past the old threshold but not the new one
+        assert_eq!(a.wallets[pot].balance, Money::koi(300));
+        a.configure_contract(pot, Some(Money::koi(250)), None)
+            .unwrap();
+        a.check_contracts();
+        assert_eq!(a.wallets[pot].balance, Money::ZERO);
+        assert_eq!(
+            a.configure_contract(pot, Some(Money::ZERO), None).err(),
+            Some(TxError::InvalidContractConfig)
+        );
+    }
+
+    #[test]
+    fn fee_total_is_the_sum_of_logged_fees() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        for (from, to, k) in [(2, 3, 50), (3, 4, 120), (4, 5, 300), (5, 2, 75)] {
+            clock.advance(600.0);
+            a.send(from, to, Money::koi(k)).unwrap();
+        }
+        clock.advance(86400.0);
+        a.send(3, 3, Money::koi(500)).unwrap();
+        let logged: Money = a.log.iter().map(|tx| tx.fee).sum();
+        assert!(logged > Money::koi(100));
+        assert_eq!(a.fees_collected, logged);
+        assert_eq!(a.stats().fees_collected, logged);
+    }
+
+    #[tokio::test]
+    async fn vesting_schedule_only_goes_up() {
+        let cfg = Config {
+            gifts: Some([("Bob".into(), Money::koi(900))].into()),
+            ..Config::default()
+        };
+        let (url, _) = serve(app_at(cfg, 20, 1, MockClock::new()), |_| {}).await;
+        let client = reqwest::Client::new();
+        let schedule = |name: &str| {
+            let url = format!("{url}/api/wallet/{name}/schedule?steps=12&interval_days=5");
+            let req = client.get(url).send();
+            async {
+                req.await
+                    .unwrap()
+                    .json::<Vec<serde_json::Value>>()
+                    .await
+                    .unwrap()
+            }
+        };
+        let points = schedule("Bob").await;
+        assert_eq!(points.len(), 12);
+        let vested: Vec<f64> = points
+            .iter()
+            .map(|p| p["vested"].as_f64().unwrap())
+            .collect();
+        assert!(vested[0] > 0.0);
+        assert!(vested.windows(2).all(|w| w[0] < w[1]), "{vested:?}");
+        // Locked funds only ever move to vested
+        for p in &points {
+            let total = p["vested"].as_f64().unwrap() + p["locked"].as_f64().unwrap();
+            assert!((total - 600.0).abs() < 1e-6);
+        }
+        assert!(schedule("Koi").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn connections_past_the_cap_are_refused_until_one_closes() {
+        let (url, _) = serve(app(20, 1), |ws| ws.slots.max = 2).await;
+        let ws_url = format!("{}/ws", url.replacen("http", "ws", 1));
+        let mut first = connect(&url).await;
+        let _second = connect(&url).await;
+        let refused = tokio_tungstenite::connect_async(ws_url.as_str()).await;
+        match refused {
+            Err(tokio_tungstenite::tungstenite::Error::Http(res)) => {
+                assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE)
+            }
+            other => panic!("expected 503, got {:?}", other.map(|_| ())),
+        }
+        first.close(None).await.unwrap();
+        // The server lets the slot go once it sees the close
+        let retry = async {
+            loop {
+                if let Ok(conn) = tokio_tungstenite::connect_async(ws_url.as_str()).await {
+                    break conn;
                 }
-                if t >= MILLIONAIRE_IDX.max(from) {
-                    t += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(tokio::time::timeout(timeout, retry).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn history_holds_only_the_wallets_own_entries() {
+        let mut a = app_at(Config::default(), 20, 1, MockClock::new());
+        a.send(2, 3, Money::koi(10)).unwrap();
+        a.send(4, 5, Money::koi(10)).unwrap();
+        a.send(3, 2, Money::koi(5)).unwrap();
+        a.send(4, 3, Money::koi(5)).unwrap();
+        let (url, _) = serve(a, |_| {}).await;
+        let history = |name: &str| {
+            let req = reqwest::get(format!("{url}/api/wallet/{name}/history"));
+            async { req.await.unwrap().json::<Vec<TxLog>>().await.unwrap() }
+        };
+        let bob = history("Bob").await;
+        let pairs: Vec<_> = bob
+            .iter()
+            .map(|e| (e.from.as_str(), e.to.as_str()))
+            .collect();
+        // Newest first, Bob's gift last
+        assert_eq!(pairs, [("Carol", "Bob"), ("Bob", "Carol"), ("Koi", "Bob")]);
+        let eve = history("Eve").await;
+        assert!(eve.iter().all(|e| e.from == "Eve" || e.to == "Eve"));
+        assert_eq!(eve.len(), 2);
+    }
+
+    #[test]
+    fn gifts_file_sets_the_starting_balances() {
+        let path = temp_path("gifts.json");
+        std::fs::write(&path, r#"{"Alice": 3000, "Carol": 600, "W00009": 1.5}"#).unwrap();
+        let gifts = load_gifts(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let cfg = Config {
+            gifts: Some(gifts),
+            ..Config::default()
+        };
+        let a = app_at(cfg, 20, 1, MockClock::new());
+        let held = |i: usize| a.wallets[i].balance + a.wallets[i].locked;
+        assert_eq!(held(1), Money::koi(3000));
+        assert_eq!(a.wallets[1].balance, Money::koi(1000));
+        assert_eq!(held(3), Money::koi(600));
+        assert_eq!(held(9), Money::from_f64(1.5));
+        assert_eq!(held(2), Money::ZERO);
+        let total = Money::koi(3600) + Money::from_f64(1.5);
+        assert_eq!(a.wallets[0].balance, a.cfg.supply - total);
+        assert!(a.log.iter().all(|tx| tx.kind == TxKind::Gift));
+        assert_eq!(a.log.len(), 3);
+    }
+
+    #[test]
+    fn airdrop_pays_out_exactly_the_total() {
+        let mut a = app_at(Config::default(), 20, 1, MockClock::new());
+        let koi = a.wallets[0].balance;
+        let received = [2, 3, 4].map(|i| a.wallets[i].received);
+        let shares = a
+            .airdrop(Money::koi(100), &[(2, 1.0), (3, 1.0), (4, 1.0)])
+            .unwrap();
+        let third = Money::from_f64(33.333333333);
+        let amounts: Vec<_> = shares.iter().map(|&(i, m, _)| (i, m)).collect();
+        let last = Money::koi(100) - third - third;
+        assert_eq!(amounts, [(2, third), (3, third), (4, last)]);
+        assert_eq!(shares.iter().map(|s| s.1).sum::<Money>(), Money::koi(100));
+        for (k, i) in [2, 3, 4].into_iter().enumerate() {
+            assert_eq!(a.wallets[i].received - received[k], amounts[k].1);
+        }
+        assert_eq!(a.wallets[0].balance, koi - Money::koi(100));
+        assert_eq!(
+            a.airdrop(Money::koi(100), &[(0, 1.0)]).err(),
+            Some(TxError::InvalidAirdrop)
+        );
+    }
+
+    #[test]
+    fn reloading_keeps_the_original_genesis() {
+        let clock = MockClock::new();
+        let a = app_at(Config::default(), 20, 1, clock.clone());
+        let genesis = a.genesis;
+        let path = temp_path("genesis.json");
+        a.save_to_path(&path).unwrap();
+        clock.advance(86400.0);
+        let notify = broadcast::channel(16).0;
+        let b =
+            App::load_from_path(&path, None, Config::default(), notify, None, None, clock).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(b.genesis, genesis);
+        assert_eq!(b.snapshot().age_seconds, 86400.0);
+    }
+
+    #[tokio::test]
+    async fn tx_lookup_tells_missing_from_evicted() {
+        let cfg = Config {
+            log_cap: 5,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let id = a.send(2, 3, Money::koi(10)).unwrap().unwrap();
+        let oldest = a.log.front().unwrap().id;
+        assert!(oldest > 0);
+        let (url, _) = serve(a, |_| {}).await;
+        let status = |id: u64| {
+            let req = reqwest::get(format!("{url}/api/tx/{id}"));
+            async { req.await.unwrap().status().as_u16() }
+        };
+        assert_eq!(status(id).await, 200);
+        assert_eq!(status(oldest - 1).await, 410);
+        assert_eq!(status(id + 1000).await, 404);
+    }
+
+    #[tokio::test]
+    async fn send_over_the_socket_is_answered_and_broadcast() {
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+        let mut a = app_at(Config::default(), 20, 1, MockClock::new());
+        let alice = key(&mut a, 1);
+        let req = signed(&alice, "Alice", "Bob", Money::koi(10), 1);
+        let (url, state) = serve(a, |_| {}).await;
+        let mut sock = connect(&url).await;
+        assert_eq!(frame(&mut sock).await["type"], "snapshot");
+        let cmd = serde_json::json!({
+            "type": "send",
+            "from": req.from,
+            "to": req.to,
+            "amount": req.amount,
+            "nonce": req.nonce,
+            "signature": req.signature,
+        });
+        sock.send(WsMessage::Text(cmd.to_string())).await.unwrap();
+        let res = frame(&mut sock).await;
+        assert_eq!(res["type"], "result");
+        assert_eq!(res["ok"], true, "{res}");
+        let id = res["id"].as_u64().unwrap();
+        // The next publish carries the send to every subscriber
+        state.write().await.publish();
+        let update = frame(&mut sock).await;
+        assert_eq!(update["type"], "delta");
+        let ids: Vec<_> = update["log"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| &e["id"])
+            .collect();
+        assert_eq!(ids, [&serde_json::Value::from(id)]);
+        let app = state.read().await;
+        assert_eq!(app.wallets[1].nonce, 1);
+        let e = app.log.back().unwrap();
+        assert_eq!((e.id, e.from.as_str(), e.to.as_str()), (id, "Alice", "Bob"));
+    }
+
+    #[test]
+    fn early_settle_follows_the_configured_fee_and_cap() {
+        let cfg = Config {
+            gifts: Some([("Bob".into(), Money::koi(3000))].into()),
+            early_fee: 0.25,
+            early_available: 0.5,
+            ..Config::default()
+        };
+        let mut a = app_at(cfg, 20, 1, MockClock::new());
+        let locked = a.wallets[2].locked;
+        let cap = locked * 0.5;
+        assert!(cap > Money::ZERO);
+        let over = cap + Money::from_f64(1e-9);
+        assert_eq!(a.send(2, 2, over), Err(TxError::ExceedsAvailable));
+        assert_eq!(a.wallets[2].locked, locked);
+        let koi = a.wallets[0].balance;
+        a.send(2, 2, cap).unwrap();
+        let fee = a.log.back().unwrap().fee;
+        assert_eq!(fee, cap * 0.25);
+        assert_eq!(a.wallets[0].balance - koi, fee);
+        assert_eq!(a.wallets[2].locked, locked - cap - fee);
+    }
+
+    #[tokio::test]
+    async fn minting_raises_koi_and_the_reported_supply() {
+        let cfg = Config {
+            gifts: Some([("Bob".into(), Money::koi(1000))].into()),
+            ..Config::default()
+        };
+        let a = app_at(cfg, 20, 1, MockClock::new());
+        let (koi, total) = (a.wallets[0].balance, a.total_supply());
+        let (url, state) = serve(a, |ws| ws.token = Some("s3cret".into())).await;
+        let client = reqwest::Client::new();
+        let res = client
+            .post(format!("{url}/api/admin/mint"))
+            .bearer_auth("s3cret")
+            .json(&serde_json::json!({ "amount": 5000 }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let minted = Money::koi(5000);
+        assert_eq!(state.read().await.wallets[0].balance, koi + minted);
+        let supply: serde_json::Value = reqwest::get(format!("{url}/api/supply"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let field = |k: &str| serde_json::from_value::<Money>(supply[k].clone()).unwrap();
+        assert_eq!(field("minted"), minted);
+        assert_eq!(field("koi_balance"), koi + minted);
+        assert_eq!(field("total_supply"), total + minted);
+        assert_eq!(state.read().await.log.back().unwrap().kind, TxKind::Mint);
+    }
+
+    #[tokio::test]
+    async fn snapshot_frames_carry_the_schema_version() {
+        let a = app(20, 1);
+        assert_eq!(a.snapshot().version, SNAPSHOT_VERSION);
+        let (url, _) = serve(a, |_| {}).await;
+        let mut sock = connect(&url).await;
+        let snap = frame(&mut sock).await;
+        assert_eq!(snap["type"], "snapshot");
+        assert_eq!(snap["version"], SNAPSHOT_VERSION);
+    }
+
+    #[test]
+    fn saved_state_reloads_identically() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        a.send(2, 3, Money::koi(10)).unwrap();
+        clock.advance(3600.0);
+        a.send(4, 4, Money::ZERO).unwrap();
+        a.burn(5, Money::koi(1)).unwrap();
+        a.mint(Money::koi(50)).unwrap();
+        let path = temp_path("reload.json");
+        a.save_to_path(&path).unwrap();
+        let notify = broadcast::channel(16).0;
+        let b =
+            App::load_from_path(&path, None, Config::default(), notify, None, None, clock).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let state = |app: &App| exact(|| serde_json::to_value(app.persisted()).unwrap());
+        assert_eq!(state(&b), state(&a));
+        assert_eq!(b.total_supply(), a.total_supply());
+    }
+
+    #[tokio::test]
+    async fn compacted_journal_replays_to_the_same_state() {
+        let state_path = temp_path("compact.json");
+        let journal_path = temp_path("compact.jsonl");
+        let (journal, task) = spawn_journal(&journal_path).unwrap();
+        let clock = MockClock::new();
+        let notify = broadcast::channel(16).0;
+        let mut a = App::new(
+            Config::default(),
+            20,
+            notify,
+            Some(journal),
+            Some(1),
+            clock.clone(),
+        );
+        // The queue journals a patch after each run of commands
+        a.journal_patch();
+        a.send(2, 3, Money::koi(10)).unwrap();
+        a.send(4, MILLIONAIRE_IDX, Money::koi(5)).unwrap();
+        a.journal_patch();
+        a.compact_journal(state_path.clone()).await.unwrap();
+        let compacted_at = a.next_id;
+        // From here on only the journal has it
+        clock.advance(3600.0);
+        a.send(2, STAKING_IDX, Money::koi(100)).unwrap();
+        let w = a.create_wallet("Zed".into(), false, None).unwrap();
+        a.send(3, w, Money::koi(2)).unwrap();
+        a.send(6, 7, Money::koi(1)).unwrap();
+        a.journal_patch();
+        a.undo().unwrap();
+        a.send(5, MILLIONAIRE_IDX, Money::koi(7)).unwrap();
+        a.journal_patch();
+        clock.advance(60.0);
+        a.settle(8);
+        a.burn(9, Money::koi(1)).unwrap();
+        a.mint(Money::koi(50)).unwrap();
+        a.journal_patch();
+        // Closing the channel lets the writer drain and stop
+        drop(a.journal.take());
+        task.await.unwrap();
+
+        let text = std::fs::read_to_string(&journal_path).unwrap();
+        for line in text.lines() {
+            match exact(|| serde_json::from_str::<PatchLine>(line))
+                .unwrap()
+                .patch
+            {
+                Some(patch) => assert!(!patch.full),
+                None => {
+                    let tx: TxLog = serde_json::from_str(line).unwrap();
+                    assert!(tx.id >= compacted_at);
                 }
-                t
-            };
-            let pct = app.rng.gen_range(0.001..=0.01);
-            let bal = app.wallets[from].balance;
-            if bal > 0.0 {
-                let amount = bal * pct;
-                let _ = app.send(from, to, amount);
-                app.check_millionaire();
             }
         }
-    });
+        let load = |replay: Option<&FsPath>| {
+            let notify = broadcast::channel(16).0;
+            let cfg = Config::default();
+            App::load_from_path(&state_path, replay, cfg, notify, None, None, clock.clone())
+                .unwrap()
+        };
+        let b = load(Some(&journal_path));
+        let saved = load(None);
+        let state = |app: &App| exact(|| serde_json::to_value(app.persisted()).unwrap());
+        assert_eq!(state(&b), state(&a));
+        assert_ne!(state(&saved), state(&a));
+        // Patches the state already covers change nothing
+        let mut again = b.persisted();
+        again.replay(&journal_path).unwrap();
+        assert_eq!(exact(|| serde_json::to_value(&again).unwrap()), state(&a));
+        let _ = std::fs::remove_file(&state_path);
+        let _ = std::fs::remove_file(&journal_path);
+    }
 
-    let app = Router::new()
-        .route("/", get(index))
-        .route("/ws", get(ws_upgrade))
-        .route("/api/send", post(send_handler))
-        .with_state(state);
+    #[tokio::test]
+    async fn interest_since_matches_the_settle_formula() {
+        let gifts = [("Alice", 100_000_000), ("Bob", 400_000_000)];
+        let cfg = Config {
+            gifts: Some(gifts.map(|(n, k)| (n.into(), Money::koi(k))).into()),
+            liquid_share: 1.0,
+            locked_share: 0.0,
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        clock.advance(30.0 * 86400.0);
+        // Settled, so today's balance is what it has held
+        a.settle(2);
+        let held = a.wallets[2].balance;
+        let erate = a.cfg.erate(a.wallets[0].balance, clock.now() - a.genesis);
+        let (url, state) = serve(a, |_| {}).await;
+        let now = clock.now();
+        let client = reqwest::Client::new();
+        let ask = |since: f64| {
+            let req = client
+                .post(format!("{url}/api/wallet/Bob/interest"))
+                .json(&serde_json::json!({ "since": since }))
+                .send();
+            async { req.await.unwrap() }
+        };
+        let res: serde_json::Value = ask(now - 10.0 * 86400.0).await.json().await.unwrap();
+        let dt = 10.0 * 86400.0 / SPY;
+        let expected = Money::from_f64(held.to_f64() * ((erate * dt).exp() - 1.0));
+        assert_eq!(
+            serde_json::from_value::<Money>(res["interest"].clone()).unwrap(),
+            expected
+        );
+        assert_eq!(ask(now + 1.0).await.status(), StatusCode::BAD_REQUEST);
+        // Asking credits nothing
+        let app = state.read().await;
+        assert_eq!(app.wallets[2].balance, held);
+    }
 
-    let addr = "0.0.0.0:3000";
-    println!("listening on {addr}");
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    #[tokio::test]
+    async fn posting_a_self_send_settles_for_the_early_fee() {
+        let cfg = Config {
+            gifts: Some([("Bob".into(), Money::koi(3000))].into()),
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        let bob = key(&mut a, 2);
+        let req = signed(&bob, "Bob", "Bob", Money::koi(100), 1);
+        clock.advance(86400.0);
+        let (url, state) = serve(a, |_| {}).await;
+        let collected = state.read().await.fees_collected;
+        let body = serde_json::json!({
+            "from": req.from,
+            "to": req.to,
+            "amount": req.amount,
+            "nonce": req.nonce,
+            "signature": req.signature,
+        });
+        let res: serde_json::Value = reqwest::Client::new()
+            .post(format!("{url}/api/send"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(res["ok"], true, "{res}");
+        assert_eq!(res["kind"], "earlysettle");
+        let money = |k: &str| serde_json::from_value::<Money>(res[k].clone()).unwrap();
+        let fee = Money::koi(100) * EARLY_FEE;
+        assert_eq!(money("fee"), fee);
+        let app = state.read().await;
+        // The day's vesting is claimed first, free of charge
+        let n = app.log.len();
+        let (claim, early) = (&app.log[n - 2], &app.log[n - 1]);
+        assert_eq!((claim.kind, claim.fee), (TxKind::Settle, Money::ZERO));
+        assert_eq!(money("claimed"), claim.amount);
+        assert_eq!(
+            (early.kind, early.amount, early.fee),
+            (TxKind::EarlySettle, Money::koi(100), fee)
+        );
+        assert_eq!(app.fees_collected, collected + fee);
+    }
+
+    #[tokio::test]
+    async fn slow_event_reader_on_a_tiny_channel_shows_in_the_metrics() {
+        let notify = broadcast::channel(2).0;
+        let a = App::new(
+            Config::default(),
+            20,
+            notify,
+            None,
+            Some(1),
+            MockClock::new(),
+        );
+        let (url, state) = serve(a, |_| {}).await;
+        let mut events = reqwest::get(format!("{url}/api/events")).await.unwrap();
+        let first = events.chunk().await.unwrap().unwrap();
+        assert!(first.starts_with(b"data: "));
+        // Nothing reads the stream while these go out, so it falls behind
+        {
+            let mut app = state.write().await;
+            for _ in 0..6 {
+                app.send(2, 3, Money::koi(1)).unwrap();
+                app.publish();
+            }
+        }
+        events.chunk().await.unwrap().unwrap();
+        let metrics = reqwest::get(format!("{url}/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let value = |name: &str| {
+            let line = metrics.lines().find(|l| l.starts_with(name)).unwrap();
+            line[name.len()..].trim().parse::<f64>().unwrap()
+        };
+        assert!(value("crypto_alice_broadcast_lag_events_total ") >= 1.0);
+        assert!(value("crypto_alice_broadcast_lagged_total ") >= 4.0);
+    }
+
+    #[test]
+    fn failed_batch_keeps_the_previous_undo() {
+        let mut a = app_at(Config::default(), 20, 1, MockClock::new());
+        let wallets = a.wallets.clone();
+        let id = a.send(2, 3, Money::koi(10)).unwrap().unwrap();
+        let too_much = a.wallets[4].balance + Money::koi(1);
+        let failed = a.batch(&[(2, 5, Money::koi(1)), (4, 5, too_much)]);
+        assert_eq!(failed.err().map(|(k, _)| k), Some(1));
+        assert_eq!(a.undo(), Ok(id));
+        assert!(a.wallets == wallets);
+    }
+
+    #[test]
+    fn reset_keeps_wallets_made_at_runtime() {
+        let (mut a, _) = with_pot(20, Money::koi(1000), Config::default());
+        let zed = a.create_wallet("Zed".into(), false, None).unwrap();
+        let names: Vec<_> = a.wallets.iter().map(|w| w.name.clone()).collect();
+        a.reset();
+        let after: Vec<_> = a.wallets.iter().map(|w| w.name.clone()).collect();
+        assert_eq!(after, names);
+        assert_eq!(a.index_of("Zed"), Some(zed));
+        // The configured lottery is still where it was, and still a contract
+        let pot = a.index_of("Pot").unwrap();
+        assert!(a.wallets[pot].contract && !a.wallets[zed].contract);
+        assert!(a.contracts.iter().any(|c| c.idx == pot));
+        assert_eq!(a.contracts[0].contributions.len(), a.wallets.len());
+    }
+
+    #[test]
+    fn read_only_views_project_without_settling() {
+        let clock = MockClock::new();
+        let mut a = app_at(Config::default(), 20, 1, clock.clone());
+        clock.advance(86400.0);
+        let (log, wallets) = (a.log.len(), a.wallets.clone());
+        let supply = a.supply();
+        let top = a.leaderboard(3);
+        let stats = a.stats();
+        assert_eq!(a.log.len(), log);
+        assert!(a.wallets == wallets);
+        // Each view agrees with the same projection, and settling matches it
+        let projected = a.projected(a.wallets.len());
+        assert_eq!(supply.koi_balance, projected[0].balance);
+        assert_eq!(stats.koi_balance, projected[0].balance);
+        let held = |w: &Wallet| w.balance + w.vested + w.locked;
+        assert_eq!(
+            top[0].holdings,
+            projected[1..]
+                .iter()
+                .filter(|w| !w.contract)
+                .map(held)
+                .max()
+                .unwrap()
+        );
+        a.settle(1);
+        assert_eq!(a.wallets[1].balance, projected[1].balance);
+    }
+
+    #[test]
+    fn by_default_one_contributor_can_win_again_at_once() {
+        let (mut a, _) = with_pot(20, Money::koi(100), Config::default());
+        let pot = a.index_of("Pot").unwrap();
+        for _ in 0..2 {
+            a.send(2, pot, Money::koi(150)).unwrap();
+            a.check_contracts();
+            assert_eq!(a.wallets[pot].balance, Money::ZERO);
+        }
+        let wins = a
+            .log
+            .iter()
+            .filter(|tx| tx.kind == TxKind::MillionairePayout);
+        assert_eq!(wins.count(), 2);
+    }
+
+    #[test]
+    fn unstaking_never_overdraws_a_nearly_empty_koi() {
+        // Gifts leave Koi with about one Koi, less than a year's reward
+        let cfg = Config {
+            gift_rest: TOTAL_SUPPLY - GIFT_ALICE - Money::koi(1),
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut a = app_at(cfg, 20, 1, clock.clone());
+        a.send(2, STAKING_IDX, Money::koi(100)).unwrap();
+        let principal = a.stakes[0].amount;
+        clock.advance(SPY);
+        let before = a.wallets[2].balance;
+        let id = a.unstake(2).unwrap();
+        assert_eq!(a.wallets[0].balance, Money::ZERO);
+        // Interest and reward together took exactly what Koi had
+        let entry = a.log.iter().find(|tx| tx.id == id).unwrap();
+        assert!(entry.amount > principal);
+        assert!(a.wallets[2].interest > Money::ZERO);
+        assert_eq!(
+            a.wallets[2].balance,
+            before + a.wallets[2].interest + entry.amount
+        );
+        assert!(a.invariant().ok);
+    }
+
+    #[test]
+    fn issued_keys_sign_for_their_wallets_and_nothing_else() {
+        let mut a = app(20, 1);
+        let issued = a.issue_keys();
+        let signers = a.wallets.iter().skip(1).filter(|w| !w.contract).count();
+        assert_eq!(issued.len(), signers);
+        assert!(a.wallets[0].pubkey.is_empty());
+        assert!(a.issue_keys().is_empty());
+        // The operator's copy is what the owner signs with
+        let path = temp_path("keys.json");
+        save_keys(&path, &issued).unwrap();
+        let secrets: BTreeMap<String, String> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let alice = SigningKey::from_bytes(&unhex(&secrets["Alice"]).unwrap());
+        let req = signed(&alice, "Alice", "Bob", Money::koi(10), 1);
+        assert!(a.submit(req).unwrap().ok);
+        let req = signed(&alice, "Bob", "Alice", Money::koi(10), 1);
+        assert_eq!(a.submit(req).err(), Some(TxError::BadSignature));
+    }
+
+    #[tokio::test]
+    async fn frames_are_plain_text_unless_deflate_is_asked_for() {
+        use futures_util::StreamExt;
+        use std::io::Read;
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+        let (url, _) = serve(app(20, 1), |_| {}).await;
+        let ws = url.replacen("http", "ws", 1);
+        let first = |query: &'static str| {
+            let url = format!("{ws}/ws{query}");
+            async move {
+                let (mut sock, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+                sock.next().await.unwrap().unwrap()
+            }
+        };
+        let WsMessage::Text(plain) = first("").await else {
+            panic!("JSON goes out as text by default");
+        };
+        let WsMessage::Binary(deflated) = first("?compress=deflate").await else {
+            panic!("compressed frames are binary");
+        };
+        let mut inflated = String::new();
+        flate2::read::ZlibDecoder::new(&deflated[..])
+            .read_to_string(&mut inflated)
+            .unwrap();
+        let parse = |s: &str| serde_json::from_str::<serde_json::Value>(s).unwrap();
+        assert_eq!(parse(&inflated)["type"], parse(&plain)["type"]);
+    }
 }